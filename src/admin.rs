@@ -0,0 +1,207 @@
+use crate::mongo::Mongo;
+use configparser::ini::Ini;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use simple_error::{SimpleError, SimpleResult};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use teloxide::{
+    prelude::*,
+    types::{ReplyMarkup, User},
+};
+
+/// What a staff member may do, every role includes the ones below it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Approves and rejects registrations
+    Moderator,
+    /// Adds, removes and readdresses peers
+    Admin,
+    /// Grants and revokes roles
+    Owner,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Moderator => write!(f, "moderator"),
+            Role::Admin => write!(f, "admin"),
+            Role::Owner => write!(f, "owner"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = SimpleError;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role.to_lowercase().as_str() {
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            "owner" => Ok(Role::Owner),
+            _ => Err(SimpleError::new(format!("Unknown role {}", role))),
+        }
+    }
+}
+
+/// Role granted to a user by an owner.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Grant {
+    pub user_id: u64,
+    pub username: String,
+    pub role: Role,
+    pub granted_by: u64,
+    pub date: DateTime,
+}
+
+/// Staff of the bot: owners from the config and the roles they granted.
+#[derive(Clone)]
+pub struct Admins {
+    /// Owners, their role cannot be revoked
+    pub ids: Arc<Vec<u64>>,
+    /// Group chat the staff shares, notifications go there instead of private chats
+    pub chat: Option<i64>,
+    mongo: Mongo,
+}
+
+impl Admins {
+    /// Reads `[Bot] AdminIds` (comma separated) and `AdminChat`, the legacy `AdminId` is one more owner.
+    pub fn from_config(config: &Ini, mongo: Mongo) -> SimpleResult<Self> {
+        let mut ids = vec![];
+        let listed = config.get("Bot", "AdminIds").unwrap_or_default();
+        let legacy = config.get("Bot", "AdminId").unwrap_or_default();
+        for id in listed.split(',').chain(legacy.split(',')) {
+            let id = id.trim();
+            if id.is_empty() {
+                continue;
+            }
+            match id.parse::<u64>() {
+                Err(why) => {
+                    return Err(SimpleError::with(
+                        &format!("Cannot parse admin id {}", id),
+                        why,
+                    ))
+                }
+                Ok(id) if !ids.contains(&id) => ids.push(id),
+                Ok(_) => (),
+            }
+        }
+        if ids.is_empty() {
+            return Err(SimpleError::new("Cannot find admin ids"));
+        }
+        let chat = match config.getint("Bot", "AdminChat") {
+            Err(why) => {
+                return Err(SimpleError::new(format!(
+                    "Cannot parse admin chat: {}",
+                    why
+                )))
+            }
+            Ok(chat) => chat,
+        };
+        Ok(Admins {
+            ids: Arc::new(ids),
+            chat,
+            mongo,
+        })
+    }
+
+    /// None if the user is not a staff member
+    pub async fn role(&self, user_id: UserId) -> Option<Role> {
+        if self.ids.contains(&user_id.0) {
+            return Some(Role::Owner);
+        }
+        self.mongo
+            .find_grant(user_id.0)
+            .await
+            .map(|grant| grant.role)
+    }
+
+    /// Staff group chat or, without it, the private chat of every staff member
+    pub async fn chats(&self) -> Vec<ChatId> {
+        if let Some(chat) = self.chat {
+            return vec![ChatId(chat)];
+        }
+        let mut ids = self.ids.to_vec();
+        for grant in self.mongo.get_grants().await {
+            if !ids.contains(&grant.user_id) {
+                ids.push(grant.user_id);
+            }
+        }
+        ids.into_iter().map(|id| ChatId(id as i64)).collect()
+    }
+
+    /// Sends the text to every staff chat, a chat that cannot be reached does not stop the rest.
+    /// Returns the messages that were sent.
+    pub async fn notify(&self, bot: &Bot, text: &str, markup: Option<ReplyMarkup>) -> Vec<Message> {
+        let mut sent = vec![];
+        for chat in self.chats().await {
+            let request = bot.send_message(chat, text);
+            let result = match &markup {
+                Some(markup) => request.reply_markup(markup.clone()).await,
+                None => request.await,
+            };
+            match result {
+                Err(why) => log::error!("Cannot notify admin chat {}: {}", chat, why),
+                Ok(message) => sent.push(message),
+            }
+        }
+        sent
+    }
+}
+
+/// Action an admin performed, kept to know who did what.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditEntry {
+    pub admin_id: u64,
+    pub admin: String,
+    pub action: String,
+    pub user_id: u64,
+    pub date: DateTime,
+}
+
+impl AuditEntry {
+    pub fn new(admin: &User, action: &str, user_id: UserId) -> Self {
+        AuditEntry {
+            admin_id: admin.id.0,
+            admin: match &admin.username {
+                Some(username) => format!("@{}", username),
+                None => admin.full_name(),
+            },
+            action: action.to_string(),
+            user_id: user_id.0,
+            date: DateTime::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn read_admins() {
+    let mongo = Mongo::new(
+        "mongodb://localhost:27017",
+        "gimmewire_test".to_string(),
+        "peers".to_string(),
+    )
+    .await;
+    let mut config = Ini::new();
+    config
+        .read(
+            "[Bot]
+AdminIds = 1, 2
+AdminId = 2"
+                .to_string(),
+        )
+        .unwrap();
+    let admins = Admins::from_config(&config, mongo.clone()).unwrap();
+    assert!(*admins.ids == vec![1, 2] && admins.chat.is_none());
+    assert!(admins.role(UserId(2)).await == Some(Role::Owner));
+    config.set("Bot", "AdminChat", Some("-100".to_string()));
+    let admins = Admins::from_config(&config, mongo.clone()).unwrap();
+    assert!(admins.chats().await == vec![ChatId(-100)]);
+    config.set("Bot", "AdminIds", Some("one".to_string()));
+    assert!(Admins::from_config(&config, mongo).is_err());
+    assert!("Admin".parse::<Role>().unwrap() == Role::Admin);
+    assert!(Role::Moderator < Role::Admin && Role::Admin < Role::Owner);
+}
@@ -0,0 +1,351 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use simple_error::{SimpleError, SimpleResult};
+use std::fmt;
+use std::io::Write;
+use std::net::IpAddr;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use wireguard_uapi::{get, set, DeviceInterface, WgSocket};
+
+/// Peer as it is currently configured on the interface.
+#[derive(Debug, Clone)]
+pub struct PeerState {
+    pub public_key: String,
+    pub allowed_ips: Vec<String>,
+    /// Address the last packet came from
+    pub endpoint: Option<String>,
+    /// Seconds since the epoch, None if the peer has never connected
+    pub last_handshake: Option<u64>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// Interface state as reported by the kernel.
+#[derive(Debug, Clone)]
+pub struct DeviceState {
+    pub peers: Vec<PeerState>,
+}
+
+#[derive(Debug)]
+pub enum WgError {
+    /// Key is not a base64 encoded 32 bytes value
+    InvalidKey(String),
+    /// Cannot spawn the wg binary
+    Io(std::io::Error),
+    /// wg binary finished with a non-zero exit code
+    Command { status: ExitStatus, stderr: String },
+    /// Cannot parse wg output
+    Parse(String),
+    /// Generic netlink request failed
+    Netlink(String),
+}
+
+impl fmt::Display for WgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WgError::InvalidKey(key) => write!(f, "Invalid WireGuard key {}", key),
+            WgError::Io(why) => write!(f, "Cannot run wg: {}", why),
+            WgError::Command { status, stderr } => {
+                write!(f, "wg finished with {}: {}", status, stderr.trim())
+            }
+            WgError::Parse(line) => write!(f, "Cannot parse wg output: {}", line),
+            WgError::Netlink(why) => write!(f, "Netlink request failed: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for WgError {}
+
+impl From<std::io::Error> for WgError {
+    fn from(why: std::io::Error) -> Self {
+        WgError::Io(why)
+    }
+}
+
+/// The way peers get applied to a WireGuard interface.
+pub trait WgBackend: Send + Sync {
+    /// Adds the peer or updates its preshared key and allowed ips, if it already exists
+    fn set_peer(
+        &self,
+        interface: &str,
+        public_key: &str,
+        preshared_key: Option<&str>,
+        allowed_ips: &[IpAddr],
+    ) -> Result<(), WgError>;
+    fn remove_peer(&self, interface: &str, public_key: &str) -> Result<(), WgError>;
+    fn device(&self, interface: &str) -> Result<DeviceState, WgError>;
+}
+
+/// Creates a backend by its config name, "auto" falls back to wg if netlink is not available.
+pub fn connect(kind: &str, wg_path: &str) -> SimpleResult<Arc<dyn WgBackend>> {
+    let cli = CliBackend {
+        wg_path: wg_path.to_string(),
+    };
+    match kind {
+        "netlink" => match WgSocket::connect() {
+            Ok(_) => Ok(Arc::new(NetlinkBackend)),
+            Err(why) => Err(SimpleError::with(
+                "Cannot connect to WireGuard over netlink",
+                why,
+            )),
+        },
+        "cli" => Ok(Arc::new(cli)),
+        "auto" => match WgSocket::connect() {
+            Ok(_) => {
+                log::info!("Using netlink WireGuard backend");
+                Ok(Arc::new(NetlinkBackend))
+            }
+            Err(why) => {
+                log::warn!(
+                    "Cannot connect to WireGuard over netlink ({}), using {}",
+                    why,
+                    wg_path
+                );
+                Ok(Arc::new(cli))
+            }
+        },
+        _ => Err(SimpleError::new(format!(
+            "Unknown WireGuard backend {}",
+            kind
+        ))),
+    }
+}
+
+/// Talks to the kernel over the "wireguard" generic netlink family.
+pub struct NetlinkBackend;
+
+impl NetlinkBackend {
+    fn socket(&self) -> Result<WgSocket, WgError> {
+        WgSocket::connect().map_err(|why| WgError::Netlink(why.to_string()))
+    }
+}
+
+impl WgBackend for NetlinkBackend {
+    fn set_peer(
+        &self,
+        interface: &str,
+        public_key: &str,
+        preshared_key: Option<&str>,
+        allowed_ips: &[IpAddr],
+    ) -> Result<(), WgError> {
+        let public_key = decode_key(public_key)?;
+        // An all-zero key removes the preshared key of the peer
+        let preshared_key = match preshared_key {
+            Some(preshared_key) => decode_key(preshared_key)?,
+            None => [0u8; 32],
+        };
+        let allowed_ips = allowed_ips
+            .iter()
+            .map(set::AllowedIp::from_ipaddr)
+            .collect();
+        let peer = set::Peer::from_public_key(&public_key)
+            .flags(vec![set::WgPeerF::ReplaceAllowedIps])
+            .preshared_key(&preshared_key)
+            .allowed_ips(allowed_ips);
+        self.socket()?
+            .set_device(set::Device::from_ifname(interface).peers(vec![peer]))
+            .map_err(|why| WgError::Netlink(why.to_string()))
+    }
+
+    fn remove_peer(&self, interface: &str, public_key: &str) -> Result<(), WgError> {
+        let public_key = decode_key(public_key)?;
+        let peer = set::Peer::from_public_key(&public_key).flags(vec![set::WgPeerF::RemoveMe]);
+        self.socket()?
+            .set_device(set::Device::from_ifname(interface).peers(vec![peer]))
+            .map_err(|why| WgError::Netlink(why.to_string()))
+    }
+
+    fn device(&self, interface: &str) -> Result<DeviceState, WgError> {
+        let device = self
+            .socket()?
+            .get_device(DeviceInterface::from_name(interface))
+            .map_err(|why| WgError::Netlink(why.to_string()))?;
+        Ok(DeviceState {
+            peers: device.peers.iter().map(peer_state).collect(),
+        })
+    }
+}
+
+fn peer_state(peer: &get::Peer) -> PeerState {
+    PeerState {
+        public_key: STANDARD.encode(peer.public_key),
+        allowed_ips: peer
+            .allowed_ips
+            .iter()
+            .map(|ip| format!("{}/{}", ip.ipaddr, ip.cidr_mask))
+            .collect(),
+        endpoint: peer.endpoint.map(|endpoint| endpoint.to_string()),
+        last_handshake: Some(peer.last_handshake_time.as_secs()).filter(|secs| *secs > 0),
+        rx_bytes: peer.rx_bytes,
+        tx_bytes: peer.tx_bytes,
+    }
+}
+
+/// Runs `wg set` and `wg show dump`, used when netlink is not available.
+pub struct CliBackend {
+    pub wg_path: String,
+}
+
+impl CliBackend {
+    fn run(&self, args: &[&str]) -> Result<String, WgError> {
+        self.run_with_input(args, None)
+    }
+
+    /// Secrets go through stdin, so they never show up in the process list
+    fn run_with_input(&self, args: &[&str], input: Option<&str>) -> Result<String, WgError> {
+        let mut child = Command::new(&self.wg_path)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(input) = input {
+            child.stdin.take().unwrap().write_all(input.as_bytes())?;
+        }
+        drop(child.stdin.take());
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(WgError::Command {
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            });
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+}
+
+impl WgBackend for CliBackend {
+    fn set_peer(
+        &self,
+        interface: &str,
+        public_key: &str,
+        preshared_key: Option<&str>,
+        allowed_ips: &[IpAddr],
+    ) -> Result<(), WgError> {
+        decode_key(public_key)?;
+        if let Some(preshared_key) = preshared_key {
+            decode_key(preshared_key)?;
+        }
+        let args = set_args(interface, public_key, preshared_key.is_some(), allowed_ips);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_with_input(&args, preshared_key).map(|_| ())
+    }
+
+    fn remove_peer(&self, interface: &str, public_key: &str) -> Result<(), WgError> {
+        decode_key(public_key)?;
+        self.run(&["set", interface, "peer", public_key, "remove"])
+            .map(|_| ())
+    }
+
+    fn device(&self, interface: &str) -> Result<DeviceState, WgError> {
+        parse_dump(&self.run(&["show", interface, "dump"])?)
+    }
+}
+
+/// Arguments of `wg set` for a peer. The preshared key is read from stdin,
+/// without one `/dev/null` removes it: wg takes an empty file, but not an empty pipe.
+fn set_args(
+    interface: &str,
+    public_key: &str,
+    preshared_key: bool,
+    allowed_ips: &[IpAddr],
+) -> Vec<String> {
+    let allowed_ips = allowed_ips
+        .iter()
+        .map(|ip| match ip {
+            IpAddr::V4(ip) => format!("{}/32", ip),
+            IpAddr::V6(ip) => format!("{}/128", ip),
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+    let key_file = match preshared_key {
+        true => "/dev/stdin",
+        false => "/dev/null",
+    };
+    [
+        "set",
+        interface,
+        "peer",
+        public_key,
+        "preshared-key",
+        key_file,
+        "allowed-ips",
+        &allowed_ips,
+    ]
+    .map(str::to_string)
+    .to_vec()
+}
+
+/// Parses `wg show <interface> dump`: the first line describes the interface, the rest are peers.
+fn parse_dump(dump: &str) -> Result<DeviceState, WgError> {
+    let mut lines = dump.lines();
+    let header: Vec<&str> = match lines.next() {
+        Some(line) => line.split('\t').collect(),
+        None => return Err(WgError::Parse("empty dump".to_string())),
+    };
+    if header.len() != 4 {
+        return Err(WgError::Parse(header.join(" ")));
+    }
+    let mut device = DeviceState { peers: vec![] };
+    for line in lines {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 8 {
+            return Err(WgError::Parse(line.to_string()));
+        }
+        let number = |field: &str| {
+            field
+                .parse::<u64>()
+                .map_err(|_| WgError::Parse(line.to_string()))
+        };
+        device.peers.push(PeerState {
+            public_key: fields[0].to_string(),
+            endpoint: Some(fields[2].to_string()).filter(|endpoint| endpoint != "(none)"),
+            allowed_ips: fields[3]
+                .split(',')
+                .filter(|ip| *ip != "(none)")
+                .map(str::to_string)
+                .collect(),
+            last_handshake: Some(number(fields[4])?).filter(|secs| *secs > 0),
+            rx_bytes: number(fields[5])?,
+            tx_bytes: number(fields[6])?,
+        });
+    }
+    Ok(device)
+}
+
+pub fn decode_key(key: &str) -> Result<[u8; 32], WgError> {
+    match STANDARD.decode(key.trim()) {
+        Ok(bytes) => bytes
+            .try_into()
+            .map_err(|_| WgError::InvalidKey(key.to_string())),
+        Err(_) => Err(WgError::InvalidKey(key.to_string())),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn parse_wg_dump() {
+    let dump = "cFJpdmF0ZUtleQ==\tuNPb1bHOu8vM0rM6H3rPzTtDVfxmcYQHNJ4ZDbGf8Ss=\t51820\toff
+Zm9vYmFyZm9vYmFyZm9vYmFyZm9vYmFyZm9vYmFyZm8=\t(none)\t203.0.113.7:41234\t10.0.0.2/32\t1700000000\t1024\t2048\t25
+YmF6cXV4YmF6cXV4YmF6cXV4YmF6cXV4YmF6cXV4YmE=\t(none)\t(none)\t(none)\t0\t0\t0\toff
+";
+    let device = parse_dump(dump).unwrap();
+    assert!(device.peers.len() == 2);
+    assert!(device.peers[0].allowed_ips == vec!["10.0.0.2/32".to_string()]);
+    assert!(device.peers[0].last_handshake == Some(1700000000));
+    assert!(device.peers[0].endpoint.as_deref() == Some("203.0.113.7:41234"));
+    assert!(device.peers[0].rx_bytes == 1024 && device.peers[0].tx_bytes == 2048);
+    assert!(device.peers[1].allowed_ips.is_empty() && device.peers[1].last_handshake.is_none());
+    assert!(device.peers[1].endpoint.is_none());
+    let ips = [
+        IpAddr::V4("10.0.0.2".parse().unwrap()),
+        IpAddr::V6("fd00::2".parse().unwrap()),
+    ];
+    let args = set_args("wg0", "key", true, &ips);
+    assert!(
+        args.join(" ")
+            == "set wg0 peer key preshared-key /dev/stdin allowed-ips 10.0.0.2/32,fd00::2/128"
+    );
+    let args = set_args("wg0", "key", false, &ips[..1]);
+    assert!(args.join(" ") == "set wg0 peer key preshared-key /dev/null allowed-ips 10.0.0.2/32");
+}
@@ -1,11 +1,20 @@
-use crate::wireguard::Peer;
+use crate::admin::{Admins, AuditEntry, Grant, Role};
+use crate::list::{self, ListQuery};
+use crate::peers_file;
+use crate::registration::{Notification, Registration, Status};
+use crate::server::{Server, Servers};
+use crate::wireguard::{Peer, PeerError};
 use crate::{mongo::Mongo, wireguard};
 use configparser::ini::Ini;
 use mongodb::bson::DateTime;
 use simple_error::SimpleError;
-use std::collections::HashMap;
 use std::sync::Arc;
-use teloxide::{prelude::*, types::InputFile, utils::command::BotCommands};
+use std::time::{SystemTime, UNIX_EPOCH};
+use teloxide::{
+    prelude::*,
+    types::{ForwardedFrom, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, User},
+    utils::command::BotCommands,
+};
 use tokio::sync::Mutex;
 
 #[derive(BotCommands, Clone)]
@@ -16,8 +25,12 @@ use tokio::sync::Mutex;
 pub enum UserCommands {
     #[command(description = "📝 Register, if you are new user.")]
     Register,
-    #[command(description = "🚀 Get WireGuard config.")]
-    GetConfig,
+    #[command(
+        description = "🚀 Get WireGuard config, optionally for a location. Use /getconfig pubkey <key> to keep your private key."
+    )]
+    GetConfig(String),
+    #[command(description = "📶 Show whether your tunnel is connected.")]
+    Status(String),
     #[command(description = "📕 Help")]
     Help,
 }
@@ -28,124 +41,432 @@ pub enum UserCommands {
 )]
 pub enum AdminCommands {
     #[command(description = "Approve new user.")]
-    Approve,
+    Approve(String),
     #[command(description = "Reject new user.")]
-    Reject,
+    Reject(String),
     #[command(description = "Remove peer")]
-    Remove,
+    Remove(String),
     #[command(description = "Add peer with any username")]
-    Add,
+    Add(String),
+    #[command(description = "Move peer to a new address")]
+    NewIp(String),
+    #[command(description = "Give a user a role: moderator, admin or owner")]
+    Grant(String),
+    #[command(description = "Take the role away from a user")]
+    Revoke(String),
+    #[command(description = "List peers: /list [all | active | never] [name prefix]")]
+    List(String),
 }
+
+impl AdminCommands {
+    /// Lowest role allowed to run the command
+    pub fn role(&self) -> Role {
+        match self {
+            AdminCommands::Approve(_) | AdminCommands::Reject(_) => Role::Moderator,
+            AdminCommands::Remove(_)
+            | AdminCommands::Add(_)
+            | AdminCommands::NewIp(_)
+            | AdminCommands::List(_) => Role::Admin,
+            AdminCommands::Grant(_) | AdminCommands::Revoke(_) => Role::Owner,
+        }
+    }
+
+    fn usage(&self) -> String {
+        let (command, args) = match self {
+            AdminCommands::Approve(_) => ("approve", TARGET_ARGS),
+            AdminCommands::Reject(_) => ("reject", TARGET_ARGS),
+            AdminCommands::Remove(_) => ("remove", TARGET_ARGS),
+            AdminCommands::Add(_) => ("add", "<@username id | id>"),
+            AdminCommands::NewIp(_) => ("newip", TARGET_ARGS),
+            AdminCommands::Grant(_) => {
+                return format!(
+                    "Usage: /grant {} <moderator | admin | owner>{} with /grant <role>",
+                    TARGET_ARGS, REPLY_USAGE
+                )
+            }
+            AdminCommands::List(_) => {
+                return "Usage: /list [all | active | never] [name prefix]".to_string()
+            }
+            AdminCommands::Revoke(_) => ("revoke", TARGET_ARGS),
+        };
+        format!("Usage: /{} {}{}", command, args, REPLY_USAGE)
+    }
+}
+
+/// Ways to name the user an admin command is about
+const TARGET_ARGS: &str = "<id | @username | @username id>";
+const REPLY_USAGE: &str = ", or reply to the notification or the user's message";
+
+/// User an admin command is about
+struct Target {
+    user_id: UserId,
+    username: String,
+}
+
+/// Arguments naming a user: `id`, `@username`, `@username id`, or nothing when replying
+fn parse_target_args(args: &[&str]) -> Option<(Option<String>, Option<u64>)> {
+    match args {
+        [] => Some((None, None)),
+        [arg] => match arg.strip_prefix('@') {
+            Some(username) if is_username(username) => Some((Some(username.to_string()), None)),
+            Some(_) => None,
+            None => Some((None, Some(arg.parse().ok()?))),
+        },
+        [username, id] => {
+            let username = username
+                .strip_prefix('@')
+                .filter(|name| is_username(name))?;
+            Some((Some(username.to_string()), Some(id.parse().ok()?)))
+        }
+        _ => None,
+    }
+}
+
+/// "None" is stored for every user without a username, so it names no one in particular
+fn is_username(username: &str) -> bool {
+    !username.is_empty() && username != "None"
+}
+
+/// Finds the user the command is about, the error is the text to answer with
+async fn resolve_target(args: &[&str], message: &Message, mongo: &Mongo) -> Result<Target, String> {
+    let (username, user_id) = match parse_target_args(args) {
+        None => return Err("Wrong format".to_string()),
+        Some(target) => target,
+    };
+    match (username, user_id) {
+        (Some(username), Some(id)) => Ok(Target {
+            user_id: UserId(id),
+            username,
+        }),
+        (None, Some(id)) => Ok(Target {
+            user_id: UserId(id),
+            username: find_username(mongo, id).await,
+        }),
+        (Some(username), None) => {
+            let user_id = match mongo.find_registration_by_username(&username).await {
+                Some(registration) => registration.user_id,
+                None => match mongo.find_by_username(&username).await {
+                    Some(peer) => peer.user_id,
+                    None => return Err(format!("Cannot find user @{}", username)),
+                },
+            };
+            Ok(Target {
+                user_id: UserId(user_id),
+                username,
+            })
+        }
+        (None, None) => match message.reply_to_message() {
+            Some(reply) => reply_target(reply, mongo).await,
+            None => Err("Wrong format".to_string()),
+        },
+    }
+}
+
+/// Finds the user the command is about, or answers with the usage if there is none
+async fn command_target(
+    bot: &Bot,
+    message: &Message,
+    cmd: &AdminCommands,
+    args: &str,
+    mongo: &Mongo,
+) -> Result<Option<Target>, teloxide::RequestError> {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    match resolve_target(&args, message, mongo).await {
+        Err(why) => {
+            bot.send_message(message.chat.id, format!("{}\n{}", why, cmd.usage()))
+                .await?;
+            Ok(None)
+        }
+        Ok(target) => Ok(Some(target)),
+    }
+}
+
+/// User a replied message is about: the author of a forwarded message,
+/// the user of a bot notification, or whoever wrote the message
+async fn reply_target(reply: &Message, mongo: &Mongo) -> Result<Target, String> {
+    let user = match reply.forward_from() {
+        Some(ForwardedFrom::User(user)) => user,
+        Some(_) => return Err("Cannot see who wrote the forwarded message".to_string()),
+        None => match reply.from() {
+            Some(user) => user,
+            None => return Err("Cannot see who wrote the message".to_string()),
+        },
+    };
+    if !user.is_bot {
+        return Ok(Target {
+            user_id: user.id,
+            username: user.username.clone().unwrap_or("None".to_string()),
+        });
+    }
+    // Only stored notifications count, other bot messages like /list pages contain numbers too
+    match mongo.find_notification(reply.chat.id.0, reply.id.0).await {
+        Some(notification) => Ok(Target {
+            user_id: UserId(notification.user_id),
+            username: find_username(mongo, notification.user_id).await,
+        }),
+        None => Err("Cannot find the user of this message".to_string()),
+    }
+}
+
+/// Username the user registered with, "None" if it is unknown
+async fn find_username(mongo: &Mongo, id: u64) -> String {
+    match mongo.find_registration(id).await {
+        Some(registration) => registration.username,
+        None => match mongo.find_by_id(id).await {
+            Some(peer) => peer.username,
+            None => "None".to_string(),
+        },
+    }
+}
+
+/// Lets the command through, if the sender's role is high enough for it
+pub async fn admin_filter(message: Message, cmd: AdminCommands, admins: Admins) -> bool {
+    match message.from() {
+        Some(user) => admins
+            .role(user.id)
+            .await
+            .is_some_and(|role| role >= cmd.role()),
+        None => false,
+    }
+}
+
 pub async fn admin_handle(
     bot: Bot,
     message: Message,
     cmd: AdminCommands,
-    chats: Arc<Mutex<HashMap<UserId, ChatId>>>,
     mongo: Mongo,
     config: Arc<Mutex<Ini>>,
+    servers: Servers,
+    admins: Admins,
 ) -> Result<(), teloxide::RequestError> {
-    let admin_chat_id = config
-        .lock()
-        .await
-        .getint("Bot", "AdminId")
-        .expect("Cannot find admin chat id")
-        .unwrap();
-    if message.chat.id != ChatId(admin_chat_id) {
-        return Ok(());
-    }
-    let args: Vec<&str> = message.text().unwrap().split(" ").collect();
-    if args.len() != 3 {
-        bot.send_message(ChatId(admin_chat_id), "Wrong format")
-            .await?;
-        return Ok(());
-    }
-    let (username, user_id) = (
-        args[1].to_string().strip_prefix("@").unwrap().to_string(),
-        UserId(args[2].parse().unwrap()),
-    );
-    match cmd {
-        AdminCommands::Approve => {
-            if mongo
-                .add(&Peer {
-                    user_id: user_id.0,
-                    username: username,
-                    private_key: None,
-                    public_key: None,
-                    ip: None,
-                    date: DateTime::now(),
-                })
-                .await
-                .is_ok()
-            {
-                bot.send_message(
-                    chats.lock().await[&user_id],
-                    "Congrats! Admin's approved your request, now you can get a config",
-                )
+    let admin = match message.from() {
+        Some(admin) => admin.clone(),
+        None => return Ok(()),
+    };
+    match &cmd {
+        AdminCommands::List(args) => {
+            let (text, buttons) = list_page(&mongo, &servers, &ListQuery::parse(args)).await;
+            bot.send_message(message.chat.id, text)
+                .reply_markup(buttons)
                 .await?;
+        }
+        AdminCommands::Approve(args) => {
+            let Target { user_id, username } =
+                match command_target(&bot, &message, &cmd, args, &mongo).await? {
+                    Some(target) => target,
+                    None => return Ok(()),
+                };
+            if approve(&bot, &mongo, &servers, user_id, username).await? {
+                audit(&mongo, &admin, "approve", user_id).await;
             }
         }
-        AdminCommands::Reject => {
-            bot.send_message(
-                chats.lock().await[&user_id],
-                "Sorry, admin's rejected your request",
-            )
-            .await?;
+        AdminCommands::Reject(args) => {
+            let Target { user_id, .. } =
+                match command_target(&bot, &message, &cmd, args, &mongo).await? {
+                    Some(target) => target,
+                    None => return Ok(()),
+                };
+            reject(&bot, &mongo, user_id).await?;
+            audit(&mongo, &admin, "reject", user_id).await;
         }
-        AdminCommands::Remove => {
-            if let Some(peer) = mongo.find_by_id(user_id.0).await {
-                let _ = wireguard::remove_peer(&peer).await;
-                if mongo.delete(&peer).await.is_ok() {
+        AdminCommands::Remove(args) => {
+            let Target { user_id, .. } =
+                match command_target(&bot, &message, &cmd, args, &mongo).await? {
+                    Some(target) => target,
+                    None => return Ok(()),
+                };
+            let peers = mongo.find_all_by_id(user_id.0).await;
+            if !peers.is_empty() {
+                let mut removed = true;
+                for peer in peers {
+                    removed &= mongo.delete(&peer).await.is_ok();
+                    if let Some(server) = servers.find(&peer.server) {
+                        let _ = wireguard::remove_peer(&peer, server).await;
+                        peers_file::sync(&mongo, &servers, server).await;
+                    }
+                }
+                audit(&mongo, &admin, "remove", user_id).await;
+                if removed {
                     bot.send_message(
-                        chats.lock().await[&user_id],
+                        user_chat(&mongo, user_id).await,
                         "You've been removed from gimmewire",
                     )
                     .await?;
                 }
             } else {
-                bot.send_message(ChatId(admin_chat_id), "Cannot find peer")
+                bot.send_message(message.chat.id, "Cannot find peer")
+                    .await?;
+            }
+        }
+        AdminCommands::NewIp(args) => {
+            let Target { user_id, .. } =
+                match command_target(&bot, &message, &cmd, args, &mongo).await? {
+                    Some(target) => target,
+                    None => return Ok(()),
+                };
+            let peers = mongo.find_all_by_id(user_id.0).await;
+            if peers.iter().all(|peer| peer.ip.is_none()) {
+                bot.send_message(message.chat.id, "Cannot find peer")
+                    .await?;
+                return Ok(());
+            }
+            audit(&mongo, &admin, "newip", user_id).await;
+            for mut peer in peers.into_iter().filter(|peer| peer.ip.is_some()) {
+                let server = match servers.find(&peer.server) {
+                    Some(server) => server,
+                    None => continue,
+                };
+                if let Err(why) = wireguard::change_ip(&mut peer, &mongo, server).await {
+                    bot.send_message(
+                        message.chat.id,
+                        format!("Cannot change address on {}: {}", server.name, why),
+                    )
                     .await?;
+                    continue;
+                }
+                peers_file::sync(&mongo, &servers, server).await;
+                bot.send_message(
+                    message.chat.id,
+                    format!(
+                        "@{} has address {} on {} now",
+                        peer.username,
+                        peer.ip.unwrap(),
+                        server.name
+                    ),
+                )
+                .await?;
+                bot.send_message(
+                    user_chat(&mongo, user_id).await,
+                    format!(
+                        "Your address on {} has changed, get a new config with /getconfig {}",
+                        server.name, server.name
+                    ),
+                )
+                .await?;
+            }
+        }
+        AdminCommands::Grant(args) => {
+            // The role goes last, so the user can be named the same way as in other commands
+            let mut args: Vec<&str> = args.split_whitespace().collect();
+            let role = match args.pop().map(str::parse::<Role>) {
+                Some(Ok(role)) => role,
+                Some(Err(why)) => {
+                    bot.send_message(message.chat.id, format!("{}\n{}", why, cmd.usage()))
+                        .await?;
+                    return Ok(());
+                }
+                None => {
+                    bot.send_message(message.chat.id, cmd.usage()).await?;
+                    return Ok(());
+                }
+            };
+            let Target { user_id, username } =
+                match command_target(&bot, &message, &cmd, &args.join(" "), &mongo).await? {
+                    Some(target) => target,
+                    None => return Ok(()),
+                };
+            let grant = Grant {
+                user_id: user_id.0,
+                username: username.clone(),
+                role,
+                granted_by: admin.id.0,
+                date: DateTime::now(),
+            };
+            if let Err(why) = mongo.add_grant(&grant).await {
+                bot.send_message(message.chat.id, format!("Cannot grant role: {}", why))
+                    .await?;
+                return Ok(());
+            }
+            audit(&mongo, &admin, &format!("grant {}", role), user_id).await;
+            bot.send_message(message.chat.id, format!("@{} is {} now", username, role))
+                .await?;
+        }
+        AdminCommands::Revoke(args) => {
+            let Target { user_id, username } =
+                match command_target(&bot, &message, &cmd, args, &mongo).await? {
+                    Some(target) => target,
+                    None => return Ok(()),
+                };
+            if admins.ids.contains(&user_id.0) {
+                bot.send_message(message.chat.id, "Owners from the config cannot be revoked")
+                    .await?;
+                return Ok(());
+            }
+            match mongo.delete_grant(user_id.0).await {
+                Err(why) => {
+                    bot.send_message(message.chat.id, format!("Cannot revoke role: {}", why))
+                        .await?;
+                }
+                Ok(false) => {
+                    bot.send_message(message.chat.id, format!("@{} has no role", username))
+                        .await?;
+                }
+                Ok(true) => {
+                    audit(&mongo, &admin, "revoke", user_id).await;
+                    bot.send_message(message.chat.id, format!("@{} has no role now", username))
+                        .await?;
+                }
             }
         }
-        AdminCommands::Add => {
+        AdminCommands::Add(args) => {
+            let Target { user_id, username } =
+                match command_target(&bot, &message, &cmd, args, &mongo).await? {
+                    Some(target) => target,
+                    None => return Ok(()),
+                };
+            let server = servers.default_server();
             if mongo
                 .add(&Peer {
                     user_id: user_id.0,
-                    username: username,
+                    username,
+                    server: server.name.clone(),
                     private_key: None,
+                    preshared_key: None,
                     public_key: None,
                     ip: None,
+                    ip6: None,
                     date: DateTime::now(),
                 })
                 .await
                 .is_ok()
             {
-                if let Some(mut peer) = mongo.find_by_id(user_id.0).await {
-                    if wireguard::add_peer(&mut peer, &mongo).await.is_ok() {
-                        if mongo.update(&peer).await.is_ok() {
-                            if let Ok(config_path) = wireguard::gen_conf(&peer, config).await {
-                                match bot
-                                    .send_document(message.chat.id, InputFile::file(config_path))
-                                    .await
-                                {
-                                    Err(why) => {
-                                        send_and_log_msg(
-                                            &bot,
-                                            &message,
-                                            Some(format!(
-                                                "Cannot send config to {}",
-                                                peer.username
-                                            )),
-                                            Some("Sorry cannot send config".to_string()),
-                                            Some(SimpleError::from(why)),
-                                            admin_chat_id,
-                                        )
-                                        .await;
-                                        let _ = wireguard::remove_peer(&peer).await; // Something like dummy rollback
-                                        return Ok(());
-                                    }
-                                    Ok(_) => (),
-                                }
-                            }
+                if let Some(mut peer) = mongo.find(user_id.0, &server.name).await {
+                    if let Err(why) = wireguard::add_peer(&mut peer, &mongo, server, None).await {
+                        bot.send_message(
+                            message.chat.id,
+                            format!("Cannot add peer to {}: {}", server.name, why),
+                        )
+                        .await?;
+                    } else if mongo.update(&peer).await.is_ok() {
+                        audit(&mongo, &admin, "add", user_id).await;
+                        peers_file::sync(&mongo, &servers, server).await;
+                        // The config holds the private key, so it never goes to the admin group
+                        let admin_chat = ChatId(admin.id.0 as i64);
+                        if let Err(why) = bot
+                            .send_document(admin_chat, config_file(&peer, config, server).await)
+                            .await
+                        {
+                            send_and_log_msg(
+                                &bot,
+                                &message,
+                                Some(format!("Cannot send config to {}", peer.username)),
+                                Some(
+                                    "Sorry cannot send config, start a private chat with me first"
+                                        .to_string(),
+                                ),
+                                Some(SimpleError::from(why)),
+                                &admins,
+                            )
+                            .await;
+                            let _ = wireguard::remove_peer(&peer, server).await; // Something like dummy rollback
+                            return Ok(());
+                        }
+                        if message.chat.id != admin_chat {
+                            bot.send_message(
+                                message.chat.id,
+                                format!("Config of @{} is sent to you privately", peer.username),
+                            )
+                            .await?;
                         }
                     }
                 }
@@ -160,17 +481,12 @@ pub async fn user_handle(
     message: Message,
     mongo: Mongo,
     cmd: UserCommands,
-    chats: Arc<Mutex<HashMap<UserId, ChatId>>>,
     config: Arc<Mutex<Ini>>,
+    servers: Servers,
+    admins: Admins,
 ) -> Result<(), teloxide::RequestError> {
     let username = message.chat.username().unwrap_or("None").to_string();
     let user_id = message.from().unwrap().id;
-    let admin_chat_id = config
-        .lock()
-        .await
-        .getint("Bot", "AdminId")
-        .expect("Cannot find admin chat id")
-        .unwrap();
     match cmd {
         UserCommands::Register => {
             if mongo
@@ -180,130 +496,276 @@ pub async fn user_handle(
             {
                 bot.send_message(message.chat.id, "This account is already registered")
                     .await?;
+            } else if mongo
+                .find_registration(user_id.0)
+                .await
+                .is_some_and(|registration| registration.status == Status::Pending)
+            {
+                bot.send_message(message.chat.id, "Request is already sent to admin")
+                    .await?;
             } else {
-                let chat_id = message.chat.id;
+                let registration = Registration {
+                    user_id: user_id.0,
+                    username: username.clone(),
+                    chat_id: message.chat.id.0,
+                    status: Status::Pending,
+                    created: DateTime::now(),
+                    updated: DateTime::now(),
+                };
+                if let Err(why) = mongo.add_registration(&registration).await {
+                    send_and_log_msg(
+                        &bot,
+                        &message,
+                        Some(format!("Cannot save registration of {}", username)),
+                        Some("Sorry cannot send request".to_string()),
+                        Some(why),
+                        &admins,
+                    )
+                    .await;
+                    return Ok(());
+                }
                 let msg = format!("@{} {}", username, user_id);
-                chats.lock().await.insert(user_id, chat_id);
-                bot.send_message(ChatId(admin_chat_id), msg).await?;
+                let buttons = InlineKeyboardMarkup::new(vec![vec![
+                    InlineKeyboardButton::callback("✅ Approve", format!("approve:{}", user_id)),
+                    InlineKeyboardButton::callback("❌ Reject", format!("reject:{}", user_id)),
+                ]]);
+                // Admins reply to the notification instead of typing the user id
+                for sent in admins.notify(&bot, &msg, Some(buttons.into())).await {
+                    let notification = Notification {
+                        chat_id: sent.chat.id.0,
+                        message_id: sent.id.0,
+                        user_id: user_id.0,
+                        date: DateTime::now(),
+                    };
+                    let _ = mongo.add_notification(&notification).await;
+                }
                 bot.send_message(message.chat.id, "Request is sent to admin")
                     .await?;
             }
         }
-        UserCommands::GetConfig => {
-            if let Some(mut peer) = mongo.find_by_id(user_id.0).await {
-                // remove old peer, if err => send message to user and to admin
-                if peer.public_key.is_some() {
-                    match wireguard::remove_peer(&peer).await {
+        UserCommands::GetConfig(args) => {
+            // `/getconfig pubkey <key> [location]` registers a key the user generated
+            let mut args = args.split_whitespace().peekable();
+            let public_key = match args.next_if_eq(&"pubkey") {
+                None => None,
+                Some(_) => match args.next().map(wireguard::parse_public_key) {
+                    None => {
+                        bot.send_message(
+                            message.chat.id,
+                            "Usage: /getconfig pubkey <output of wg pubkey> [location]",
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                    Some(Err(why)) => {
+                        bot.send_message(message.chat.id, why.to_string()).await?;
+                        return Ok(());
+                    }
+                    Some(Ok(public_key)) => Some(public_key),
+                },
+            };
+            let location: Vec<&str> = args.collect();
+            let server = match select_server(&servers, &location.join(" ")) {
+                Err(msg) => {
+                    bot.send_message(message.chat.id, msg).await?;
+                    return Ok(());
+                }
+                Ok(server) => server,
+            };
+            if mongo.find_by_id(user_id.0).await.is_some() {
+                // A user gets a new peer record the first time they ask for this server
+                let mut peer = match mongo.find(user_id.0, &server.name).await {
+                    Some(peer) => peer,
+                    None => Peer {
+                        user_id: user_id.0,
+                        username,
+                        server: server.name.clone(),
+                        private_key: None,
+                        preshared_key: None,
+                        public_key: None,
+                        ip: None,
+                        ip6: None,
+                        date: DateTime::now(),
+                    },
+                };
+                if let Some(public_key) = &public_key {
+                    match wireguard::key_in_use(public_key, &peer, &mongo, &servers, server).await {
                         Err(why) => {
                             send_and_log_msg(
                                 &bot,
                                 &message,
-                                Some(format!("Cannot remove existing peer {}", peer.username)),
+                                Some(format!("Cannot check key of {}", peer.username)),
                                 Some("Sorry cannot generate config".to_string()),
                                 Some(why),
-                                admin_chat_id,
+                                &admins,
                             )
                             .await;
                             return Ok(());
                         }
-                        Ok(_) => (),
+                        Ok(true) => {
+                            bot.send_message(
+                                message.chat.id,
+                                "This public key is already in use, generate a new key pair",
+                            )
+                            .await?;
+                            return Ok(());
+                        }
+                        Ok(false) => (),
                     }
                 }
-                // Add peer to wireguard, if err => send message to user and to admin
-                match wireguard::add_peer(&mut peer, &mongo).await {
-                    Err(why) => {
+                // Add peer to wireguard, it replaces the old one, if err => send message to user and to admin
+                match wireguard::add_peer(&mut peer, &mongo, server, public_key).await {
+                    Err(PeerError::PoolExhausted(why)) => {
                         send_and_log_msg(
                             &bot,
                             &message,
-                            Some(format!("Cannot add peer {}", peer.username)),
-                            Some("Sorry cannot generate config".to_string()),
-                            Some(why),
-                            admin_chat_id,
+                            Some(format!(
+                                "Cannot add peer {} to {}: {}",
+                                peer.username, server.name, why
+                            )),
+                            Some(
+                                "Sorry, there are no free addresses, admin is notified".to_string(),
+                            ),
+                            Some(SimpleError::from(why)),
+                            &admins,
                         )
                         .await;
                         return Ok(());
                     }
-                    Ok(_) => (),
-                };
-                // Update peer in db, if err => send message to user and to admin
-                match mongo.update(&peer).await {
-                    Err(why) => {
-                        let _ = wireguard::remove_peer(&peer).await; // Something like dummy rollback
+                    Err(PeerError::KeyInUse) => {
+                        bot.send_message(
+                            message.chat.id,
+                            "This public key is already in use, generate a new key pair",
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                    Err(PeerError::Failed(why)) => {
                         send_and_log_msg(
                             &bot,
                             &message,
-                            Some(format!("Cannot update peer {}", peer.username)),
+                            Some(format!("Cannot add peer {}", peer.username)),
                             Some("Sorry cannot generate config".to_string()),
                             Some(why),
-                            admin_chat_id,
+                            &admins,
                         )
                         .await;
                         return Ok(());
                     }
                     Ok(_) => (),
                 }
-                // If everything is ok => generate and send config
-                if let Ok(config_path) = wireguard::gen_conf(&peer, config).await {
-                    match bot
-                        .send_document(message.chat.id, InputFile::file(config_path))
-                        .await
-                    {
-                        Err(why) => {
-                            send_and_log_msg(
-                                &bot,
-                                &message,
-                                Some(format!("Cannot send config to {}", peer.username)),
-                                Some("Sorry cannot send config".to_string()),
-                                Some(SimpleError::from(why)),
-                                admin_chat_id,
-                            )
-                            .await;
-                            let _ = wireguard::remove_peer(&peer).await; // Something like dummy rollback
-                            return Ok(());
-                        }
-                        Ok(_) => (),
-                    }
-                    // If everything is ok => send message to user
-                    match bot
-                        .send_message(message.chat.id, "Open it with WireGuard")
-                        .await
-                    {
-                        Err(why) => {
-                            send_and_log_msg(
-                                &bot,
-                                &message,
-                                Some(format!("Cannot send success message to {}", peer.username)),
-                                None,
-                                Some(SimpleError::from(why)),
-                                admin_chat_id,
-                            )
-                            .await
-                        }
-                        Ok(_) => (),
-                    }
-                } else {
+                // Update peer in db, if err => send message to user and to admin
+                if let Err(why) = mongo.update(&peer).await {
+                    let _ = wireguard::remove_peer(&peer, server).await; // Something like dummy rollback
                     send_and_log_msg(
                         &bot,
                         &message,
-                        Some(format!("Cannot create config for {}", peer.username)),
+                        Some(format!("Cannot update peer {}", peer.username)),
                         Some("Sorry cannot generate config".to_string()),
-                        None,
-                        admin_chat_id,
+                        Some(why),
+                        &admins,
                     )
                     .await;
+                    return Ok(());
+                }
+                peers_file::sync(&mongo, &servers, server).await;
+                // If everything is ok => generate and send config
+                if let Err(why) = bot
+                    .send_document(message.chat.id, config_file(&peer, config, server).await)
+                    .await
+                {
+                    send_and_log_msg(
+                        &bot,
+                        &message,
+                        Some(format!("Cannot send config to {}", peer.username)),
+                        Some("Sorry cannot send config".to_string()),
+                        Some(SimpleError::from(why)),
+                        &admins,
+                    )
+                    .await;
+                    let _ = wireguard::remove_peer(&peer, server).await; // Something like dummy rollback
+                    return Ok(());
+                }
+                // If everything is ok => send message to user
+                let text = match peer.private_key {
+                    Some(_) => "Open it with WireGuard",
+                    None => "Put your private key into PrivateKey and open it with WireGuard",
+                };
+                if let Err(why) = bot.send_message(message.chat.id, text).await {
+                    send_and_log_msg(
+                        &bot,
+                        &message,
+                        Some(format!("Cannot send success message to {}", peer.username)),
+                        None,
+                        Some(SimpleError::from(why)),
+                        &admins,
+                    )
+                    .await
                 }
             } else {
                 bot.send_message(message.chat.id, "Register first").await?;
             }
         }
+        UserCommands::Status(args) => {
+            // Admins see everyone, or the user they name or reply to
+            let admin = admins.role(user_id).await >= Some(Role::Admin);
+            let args: Vec<&str> = args.split_whitespace().collect();
+            let peers = if !admin {
+                mongo.find_all_by_id(user_id.0).await
+            } else if args.is_empty() && message.reply_to_message().is_none() {
+                match mongo.get_peers().await {
+                    Err(why) => {
+                        bot.send_message(message.chat.id, format!("Cannot read peers: {}", why))
+                            .await?;
+                        return Ok(());
+                    }
+                    Ok(peers) => peers,
+                }
+            } else {
+                match resolve_target(&args, &message, &mongo).await {
+                    Err(why) => {
+                        bot.send_message(
+                            message.chat.id,
+                            format!("{}\nUsage: /status [id | @username]", why),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                    Ok(target) => mongo.find_all_by_id(target.user_id.0).await,
+                }
+            };
+            if peers.is_empty() {
+                let msg = if admin {
+                    "No peers found"
+                } else {
+                    "Register first"
+                };
+                bot.send_message(message.chat.id, msg).await?;
+                return Ok(());
+            }
+            let states = servers.peer_states();
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let lines: Vec<String> = peers
+                .iter()
+                .map(|peer| {
+                    let state = peer.public_key.as_ref().and_then(|key| states.get(key));
+                    list::status(peer, state, now)
+                })
+                .collect();
+            send_lines(&bot, message.chat.id, &lines).await?;
+        }
         UserCommands::Help => {
             bot.send_message(
                 message.chat.id,
                 "Hello!😉 Quick start:
 0. 📱 Install WireGuard client from App Store.
 1. 📝 Register
-2. 🚀 Get config
+2. 🚀 Get config, /getconfig <location> if there are several
 3. 🔥 Open config with WireGuard client
+4. 📶 Check the connection with /status
              ",
             )
             .await?;
@@ -313,25 +775,304 @@ pub async fn user_handle(
     Ok(())
 }
 
+/// Approve / Reject buttons of the registration notification
+pub async fn callback_handle(
+    bot: Bot,
+    query: CallbackQuery,
+    mongo: Mongo,
+    servers: Servers,
+    admins: Admins,
+) -> Result<(), teloxide::RequestError> {
+    let message = match query.message {
+        Some(message) if admins.role(query.from.id).await.is_some() => message,
+        _ => {
+            bot.answer_callback_query(query.id).await?;
+            return Ok(());
+        }
+    };
+    if let Some(list) = query.data.as_deref().and_then(ListQuery::from_callback) {
+        if admins.role(query.from.id).await < Some(Role::Admin) {
+            bot.answer_callback_query(query.id).await?;
+            return Ok(());
+        }
+        let (text, buttons) = list_page(&mongo, &servers, &list).await;
+        bot.answer_callback_query(query.id).await?;
+        // Peers may change between pages, an unchanged page is not an error worth answering
+        if let Err(why) = bot
+            .edit_message_text(message.chat.id, message.id, text)
+            .reply_markup(buttons)
+            .await
+        {
+            log::warn!("Cannot show peers page: {}", why);
+        }
+        return Ok(());
+    }
+    let decision = query.data.as_deref().and_then(|data| {
+        let (action, user_id) = data.split_once(':')?;
+        Some((action.to_string(), UserId(user_id.parse().ok()?)))
+    });
+    let (action, user_id) = match decision {
+        Some(decision) => decision,
+        None => {
+            bot.answer_callback_query(query.id)
+                .text("Unknown action")
+                .await?;
+            return Ok(());
+        }
+    };
+    let registration = match mongo.find_registration(user_id.0).await {
+        Some(registration) => registration,
+        None => {
+            bot.answer_callback_query(query.id)
+                .text("Cannot find registration")
+                .await?;
+            return Ok(());
+        }
+    };
+    // Another admin may have pressed the button or used the command already
+    if registration.status != Status::Pending {
+        bot.answer_callback_query(query.id)
+            .text("Request is already decided")
+            .await?;
+        bot.edit_message_reply_markup(message.chat.id, message.id)
+            .await?;
+        return Ok(());
+    }
+    let verdict = match action.as_str() {
+        "approve" => {
+            if !approve(&bot, &mongo, &servers, user_id, registration.username).await? {
+                bot.answer_callback_query(query.id)
+                    .text("Cannot approve request")
+                    .await?;
+                return Ok(());
+            }
+            audit(&mongo, &query.from, "approve", user_id).await;
+            "✅ Approved"
+        }
+        "reject" => {
+            reject(&bot, &mongo, user_id).await?;
+            audit(&mongo, &query.from, "reject", user_id).await;
+            "❌ Rejected"
+        }
+        _ => {
+            bot.answer_callback_query(query.id)
+                .text("Unknown action")
+                .await?;
+            return Ok(());
+        }
+    };
+    bot.answer_callback_query(query.id).await?;
+    let admin = match &query.from.username {
+        Some(username) => format!("@{}", username),
+        None => query.from.full_name(),
+    };
+    let date = DateTime::now().try_to_rfc3339_string().unwrap_or_default();
+    bot.edit_message_text(
+        message.chat.id,
+        message.id,
+        format!(
+            "{}\n{} by {} at {}",
+            message.text().unwrap_or_default(),
+            verdict,
+            admin,
+            date
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Page of peers with buttons to the previous and the next one
+async fn list_page(
+    mongo: &Mongo,
+    servers: &Servers,
+    query: &ListQuery,
+) -> (String, InlineKeyboardMarkup) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let peers = match mongo.get_peers().await {
+        Err(why) => {
+            return (
+                format!("Cannot read peers: {}", why),
+                InlineKeyboardMarkup::default(),
+            )
+        }
+        Ok(peers) => peers,
+    };
+    let (text, pages) = list::render(&peers, &list::handshakes(servers), query, now);
+    let page = query.page.min(pages.saturating_sub(1));
+    let mut buttons = vec![];
+    if page > 0 {
+        buttons.push(InlineKeyboardButton::callback(
+            "⬅️ Previous",
+            query.callback_data(page - 1),
+        ));
+    }
+    if page + 1 < pages {
+        buttons.push(InlineKeyboardButton::callback(
+            "Next ➡️",
+            query.callback_data(page + 1),
+        ));
+    }
+    if buttons.is_empty() {
+        return (text, InlineKeyboardMarkup::default());
+    }
+    (text, InlineKeyboardMarkup::new(vec![buttons]))
+}
+
+/// Adds the user as a keyless peer of the default server, false if it cannot be stored
+async fn approve(
+    bot: &Bot,
+    mongo: &Mongo,
+    servers: &Servers,
+    user_id: UserId,
+    username: String,
+) -> Result<bool, teloxide::RequestError> {
+    if mongo
+        .add(&Peer {
+            user_id: user_id.0,
+            username,
+            server: servers.default_server().name.clone(),
+            private_key: None,
+            preshared_key: None,
+            public_key: None,
+            ip: None,
+            ip6: None,
+            date: DateTime::now(),
+        })
+        .await
+        .is_err()
+    {
+        return Ok(false);
+    }
+    let _ = mongo
+        .set_registration_status(user_id.0, Status::Approved)
+        .await;
+    bot.send_message(
+        user_chat(mongo, user_id).await,
+        "Congrats! Admin's approved your request, now you can get a config",
+    )
+    .await?;
+    Ok(true)
+}
+
+async fn reject(bot: &Bot, mongo: &Mongo, user_id: UserId) -> Result<(), teloxide::RequestError> {
+    let _ = mongo
+        .set_registration_status(user_id.0, Status::Rejected)
+        .await;
+    bot.send_message(
+        user_chat(mongo, user_id).await,
+        "Sorry, admin's rejected your request",
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn parse_targets() {
+    assert!(parse_target_args(&[]) == Some((None, None)));
+    assert!(parse_target_args(&["1234"]) == Some((None, Some(1234))));
+    assert!(parse_target_args(&["@user"]) == Some((Some("user".to_string()), None)));
+    assert!(parse_target_args(&["@user", "1234"]) == Some((Some("user".to_string()), Some(1234))));
+    for args in [
+        &["user"][..],
+        &["@"],
+        &["@None"],
+        &["user", "1234"],
+        &["@user", "id"],
+        &["1", "2", "3"],
+    ] {
+        assert!(parse_target_args(args).is_none());
+    }
+}
+
+/// Keeps who performed the action, a failed write is only logged
+async fn audit(mongo: &Mongo, admin: &User, action: &str, user_id: UserId) {
+    let entry = AuditEntry::new(admin, action, user_id);
+    log::info!(
+        "{} ({}) {} {}",
+        entry.admin,
+        entry.admin_id,
+        action,
+        user_id
+    );
+    let _ = mongo.add_audit(&entry).await;
+}
+
+/// Chat the user registered from, private chats share the id with the user
+async fn user_chat(mongo: &Mongo, user_id: UserId) -> ChatId {
+    match mongo.find_registration(user_id.0).await {
+        Some(registration) => ChatId(registration.chat_id),
+        None => ChatId(user_id.0 as i64),
+    }
+}
+
+/// Finds the server the user asked for, or tells which locations are available
+fn select_server<'a>(servers: &'a Servers, location: &str) -> Result<&'a Server, String> {
+    if location.is_empty() {
+        if servers.len() == 1 {
+            return Ok(servers.default_server());
+        }
+        return Err(format!(
+            "Choose a location: /getconfig <{}>",
+            servers.names().join("|")
+        ));
+    }
+    servers.find(&location.to_lowercase()).ok_or(format!(
+        "Unknown location {}, available: {}",
+        location,
+        servers.names().join(", ")
+    ))
+}
+
+/// Config of the peer as a file named after the server, rendered in memory
+async fn config_file(peer: &Peer, config: Arc<Mutex<Ini>>, server: &Server) -> InputFile {
+    InputFile::memory(wireguard::gen_conf(peer, config, server).await)
+        .file_name(wireguard::conf_name(&server.name))
+}
+
+/// Splits the lines into messages that fit into the Telegram limit
+async fn send_lines(
+    bot: &Bot,
+    chat: ChatId,
+    lines: &[String],
+) -> Result<(), teloxide::RequestError> {
+    const MESSAGE_LEN: usize = 4096;
+    let mut text = String::new();
+    for line in lines {
+        if !text.is_empty() && text.len() + line.len() + 1 > MESSAGE_LEN {
+            bot.send_message(chat, text).await?;
+            text = String::new();
+        }
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push_str(line);
+    }
+    if !text.is_empty() {
+        bot.send_message(chat, text).await?;
+    }
+    Ok(())
+}
+
 async fn send_and_log_msg(
     bot: &Bot,
     message: &Message,
     admin_msg: Option<String>,
     user_msg: Option<String>,
     err: Option<SimpleError>,
-    admin_chat_id: i64,
+    admins: &Admins,
 ) {
     if let Some(msg) = user_msg {
-        match bot.send_message(message.chat.id, msg).await {
-            Err(why) => log::error!("{}", why),
-            Ok(_) => (),
+        if let Err(why) = bot.send_message(message.chat.id, msg).await {
+            log::error!("{}", why);
         }
     }
     if let Some(msg) = admin_msg {
-        match bot.send_message(ChatId(admin_chat_id), msg).await {
-            Err(why) => log::error!("{}", why),
-            Ok(_) => (),
-        }
+        admins.notify(bot, &msg, None).await;
     }
     if let Some(error) = err {
         log::error!("{}", error);
@@ -0,0 +1,164 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use configparser::ini::Ini;
+use rand_core::{OsRng, RngCore};
+use simple_error::{SimpleError, SimpleResult};
+use std::fs;
+
+/// Environment variable with the keys, used when `[Mongo] KeyFile` is not set
+const KEYS_ENV: &str = "GIMMEWIRE_KEYS";
+/// Sealed values look like `aead:<key id>:<base64 of nonce and ciphertext>`
+const PREFIX: &str = "aead:";
+const NONCE_LEN: usize = 24;
+
+/// Keys the secrets of the db are encrypted with.
+/// New values are sealed with the first key, the rest only open values sealed before a rotation.
+#[derive(Clone)]
+pub struct Keyring {
+    keys: Vec<(String, Key)>,
+}
+
+impl Keyring {
+    /// Reads `[Mongo] KeyFile` or the `GIMMEWIRE_KEYS` variable, None if neither is set
+    pub fn load(config: &Ini) -> SimpleResult<Option<Self>> {
+        let text = match config.get("Mongo", "KeyFile") {
+            Some(path) => match fs::read_to_string(&path) {
+                Err(why) => {
+                    return Err(SimpleError::with(
+                        &format!("Cannot read key file {}", path),
+                        why,
+                    ))
+                }
+                Ok(text) => text,
+            },
+            None => match std::env::var(KEYS_ENV) {
+                Ok(text) => text,
+                Err(_) => return Ok(None),
+            },
+        };
+        Keyring::parse(&text).map(Some)
+    }
+
+    /// One `<key id>:<base64 of 32 bytes>` per line or comma, the current key goes first
+    pub fn parse(text: &str) -> SimpleResult<Self> {
+        let mut keys: Vec<(String, Key)> = vec![];
+        for line in text.split(['\n', ',']) {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, key) = match line.split_once(':') {
+                Some((id, key)) => (id.trim(), key.trim()),
+                None => return Err(SimpleError::new("Keys must look like <id>:<base64 key>")),
+            };
+            if id.is_empty()
+                || !id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(SimpleError::new(format!("Invalid key id {}", id)));
+            }
+            if keys.iter().any(|(known, _)| known == id) {
+                return Err(SimpleError::new(format!("Duplicate key id {}", id)));
+            }
+            let key: [u8; 32] = match STANDARD.decode(key).map(<[u8; 32]>::try_from) {
+                Ok(Ok(key)) => key,
+                _ => {
+                    return Err(SimpleError::new(format!(
+                        "Key {} is not 32 base64 bytes",
+                        id
+                    )))
+                }
+            };
+            keys.push((id.to_string(), Key::from(key)));
+        }
+        if keys.is_empty() {
+            return Err(SimpleError::new("Cannot find any encryption key"));
+        }
+        Ok(Keyring { keys })
+    }
+
+    /// `context` binds the value to its document, so a ciphertext copied to another peer does not open
+    pub fn seal(&self, plaintext: &str, context: &str) -> SimpleResult<String> {
+        let (id, key) = &self.keys[0];
+        let mut nonce = [0u8; NONCE_LEN];
+        if let Err(why) = OsRng.try_fill_bytes(&mut nonce) {
+            return Err(SimpleError::with("Cannot generate nonce", why));
+        }
+        let payload = Payload {
+            msg: plaintext.as_bytes(),
+            aad: context.as_bytes(),
+        };
+        let ciphertext =
+            match XChaCha20Poly1305::new(key).encrypt(XNonce::from_slice(&nonce), payload) {
+                Err(_) => return Err(SimpleError::new("Cannot encrypt value")),
+                Ok(ciphertext) => ciphertext,
+            };
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(format!("{}{}:{}", PREFIX, id, STANDARD.encode(sealed)))
+    }
+
+    /// Values that are not sealed are returned as they are, they were written before encryption
+    pub fn open(&self, value: &str, context: &str) -> SimpleResult<String> {
+        let (id, sealed) = match value
+            .strip_prefix(PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+        {
+            Some(parts) => parts,
+            None => return Ok(value.to_string()),
+        };
+        let key = match self.keys.iter().find(|(known, _)| known == id) {
+            Some((_, key)) => key,
+            None => return Err(SimpleError::new(format!("Unknown key id {}", id))),
+        };
+        let sealed = match STANDARD.decode(sealed) {
+            Ok(sealed) if sealed.len() > NONCE_LEN => sealed,
+            _ => return Err(SimpleError::new("Cannot decode sealed value")),
+        };
+        let payload = Payload {
+            msg: &sealed[NONCE_LEN..],
+            aad: context.as_bytes(),
+        };
+        match XChaCha20Poly1305::new(key).decrypt(XNonce::from_slice(&sealed[..NONCE_LEN]), payload)
+        {
+            Err(_) => Err(SimpleError::new(format!(
+                "Cannot decrypt value with key {}",
+                id
+            ))),
+            Ok(plaintext) => String::from_utf8(plaintext)
+                .map_err(|why| SimpleError::with("Decrypted value is not text", why)),
+        }
+    }
+
+    pub fn is_sealed(value: &str) -> bool {
+        value.starts_with(PREFIX)
+    }
+
+    /// False for plaintext and values sealed with an older key, they are sealed again on rotation
+    pub fn is_current(&self, value: &str) -> bool {
+        value.starts_with(&format!("{}{}:", PREFIX, self.keys[0].0))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn seal_and_open() {
+    let old = Keyring::parse(&format!("old:{}", STANDARD.encode([1u8; 32]))).unwrap();
+    let keyring = Keyring::parse(&format!(
+        "new:{}\nold:{}",
+        STANDARD.encode([2u8; 32]),
+        STANDARD.encode([1u8; 32])
+    ))
+    .unwrap();
+    let sealed = old.seal("secret", "1:default").unwrap();
+    assert!(sealed.starts_with("aead:old:") && !sealed.contains("secret"));
+    assert!(keyring.open(&sealed, "1:default").unwrap() == "secret");
+    assert!(keyring.open(&sealed, "2:default").is_err());
+    assert!(!keyring.is_current(&sealed) && !keyring.is_current("secret"));
+    let resealed = keyring.seal("secret", "1:default").unwrap();
+    assert!(keyring.is_current(&resealed) && old.open(&resealed, "1:default").is_err());
+    assert!(keyring.open("plain", "1:default").unwrap() == "plain");
+    assert!(Keyring::parse("new:short").is_err() && Keyring::parse("").is_err());
+}
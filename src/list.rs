@@ -0,0 +1,265 @@
+use crate::backend::PeerState;
+use crate::server::Servers;
+use crate::wireguard::Peer;
+use std::collections::HashMap;
+
+const PAGE_SIZE: usize = 10;
+/// A handshake this recent means the tunnel is in use, WireGuard rekeys every two minutes
+const ACTIVE_SECS: u64 = 180;
+/// Telegram limit of the callback data
+const CALLBACK_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    All,
+    /// Handshake within the last few minutes
+    Active,
+    /// Never had a handshake, including approved users without a config
+    Never,
+}
+
+impl Filter {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Filter::All => "all",
+            Filter::Active => "active",
+            Filter::Never => "never",
+        }
+    }
+
+    fn parse(filter: &str) -> Option<Self> {
+        match filter {
+            "all" => Some(Filter::All),
+            "active" => Some(Filter::Active),
+            "never" => Some(Filter::Never),
+            _ => None,
+        }
+    }
+}
+
+/// Page of `/list`, the buttons carry it to show the next one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListQuery {
+    pub filter: Filter,
+    /// Beginning of the username
+    pub prefix: String,
+    pub page: usize,
+}
+
+impl ListQuery {
+    /// `[all | active | never] [prefix]`, anything else is taken as the prefix
+    pub fn parse(args: &str) -> Self {
+        let mut args = args.split_whitespace().peekable();
+        let filter = match args
+            .peek()
+            .and_then(|arg| Filter::parse(&arg.to_lowercase()))
+        {
+            Some(filter) => {
+                args.next();
+                filter
+            }
+            None => Filter::All,
+        };
+        ListQuery {
+            filter,
+            prefix: args
+                .next()
+                .unwrap_or_default()
+                .trim_start_matches('@')
+                .to_lowercase(),
+            page: 0,
+        }
+    }
+
+    /// `list:<page>:<filter>:<prefix>`, the prefix is cut to fit into the callback data
+    pub fn callback_data(&self, page: usize) -> String {
+        let mut data = format!("list:{}:{}:", page, self.filter.as_str());
+        for c in self.prefix.chars() {
+            if data.len() + c.len_utf8() > CALLBACK_LEN {
+                break;
+            }
+            data.push(c);
+        }
+        data
+    }
+
+    pub fn from_callback(data: &str) -> Option<Self> {
+        let mut parts = data.splitn(4, ':');
+        if parts.next()? != "list" {
+            return None;
+        }
+        Some(ListQuery {
+            page: parts.next()?.parse().ok()?,
+            filter: Filter::parse(parts.next()?)?,
+            prefix: parts.next()?.to_string(),
+        })
+    }
+}
+
+/// Last handshakes of the peers of every server by public key
+pub fn handshakes(servers: &Servers) -> HashMap<String, u64> {
+    servers
+        .peer_states()
+        .into_iter()
+        .filter_map(|(key, state)| Some((key, state.last_handshake?)))
+        .collect()
+}
+
+/// Text of the requested page and the number of pages
+pub fn render(
+    peers: &[Peer],
+    handshakes: &HashMap<String, u64>,
+    query: &ListQuery,
+    now: u64,
+) -> (String, usize) {
+    let handshake = |peer: &Peer| {
+        peer.public_key
+            .as_ref()
+            .and_then(|key| handshakes.get(key))
+            .copied()
+    };
+    let mut peers: Vec<&Peer> = peers
+        .iter()
+        .filter(|peer| peer.username.to_lowercase().starts_with(&query.prefix))
+        .filter(|peer| match query.filter {
+            Filter::All => true,
+            Filter::Active => {
+                handshake(peer).is_some_and(|at| now.saturating_sub(at) < ACTIVE_SECS)
+            }
+            Filter::Never => handshake(peer).is_none(),
+        })
+        .collect();
+    if peers.is_empty() {
+        return ("No peers found".to_string(), 0);
+    }
+    peers.sort_by(|a, b| {
+        (a.username.to_lowercase(), &a.server).cmp(&(b.username.to_lowercase(), &b.server))
+    });
+    let pages = peers.len().div_ceil(PAGE_SIZE);
+    let page = query.page.min(pages - 1);
+    let from = page * PAGE_SIZE;
+    let to = (from + PAGE_SIZE).min(peers.len());
+    let mut text = format!("Peers {}-{} of {}\n", from + 1, to, peers.len());
+    for peer in &peers[from..to] {
+        let ip = match peer.ip {
+            Some(ip) => ip.to_string(),
+            None => "no config".to_string(),
+        };
+        let created = peer.date.try_to_rfc3339_string().unwrap_or_default();
+        let connected = match handshake(peer) {
+            Some(at) => format!("handshake {}", ago(now.saturating_sub(at))),
+            None => "never connected".to_string(),
+        };
+        text.push_str(&format!(
+            "\n@{} ({}) {} on {}, created {}, {}",
+            peer.username,
+            peer.user_id,
+            ip,
+            peer.server,
+            created.get(..10).unwrap_or(&created),
+            connected
+        ));
+    }
+    (text, pages)
+}
+
+/// Tunnel state of the peer for `/status`
+pub fn status(peer: &Peer, state: Option<&PeerState>, now: u64) -> String {
+    let name = format!("@{} on {}", peer.username, peer.server);
+    let ip = match peer.ip {
+        Some(ip) => ip,
+        None => return format!("{}: no config yet, get it with /getconfig", name),
+    };
+    let state = match state {
+        Some(state) => state,
+        None => return format!("{} ({}): not on the interface", name, ip),
+    };
+    match state.last_handshake {
+        None => format!("{} ({}): never connected", name, ip),
+        Some(at) => format!(
+            "{} ({}): handshake {}, endpoint {}, received {}, sent {}",
+            name,
+            ip,
+            ago(now.saturating_sub(at)),
+            state.endpoint.as_deref().unwrap_or("unknown"),
+            bytes(state.rx_bytes),
+            bytes(state.tx_bytes)
+        ),
+    }
+}
+
+fn bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+pub fn ago(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn render_pages() {
+    use mongodb::bson::DateTime;
+    let query = ListQuery::parse("active @Al");
+    assert!(query.filter == Filter::Active && query.prefix == "al");
+    assert!(
+        ListQuery::from_callback(&query.callback_data(2))
+            .unwrap()
+            .page
+            == 2
+    );
+    assert!(ListQuery::parse("bob").filter == Filter::All);
+    let peers: Vec<Peer> = (0..12)
+        .map(|i| Peer {
+            user_id: i,
+            username: format!("alice{:02}", i),
+            server: "default".to_string(),
+            public_key: Some(format!("key{}", i)),
+            private_key: None,
+            preshared_key: None,
+            ip: Some(std::net::Ipv4Addr::new(10, 0, 0, i as u8 + 2)),
+            ip6: None,
+            date: DateTime::from_millis(0),
+        })
+        .collect();
+    let handshakes = HashMap::from([("key0".to_string(), 1000), ("key1".to_string(), 100)]);
+    let (text, pages) = render(&peers, &handshakes, &ListQuery::parse(""), 1010);
+    assert!(pages == 2 && text.starts_with("Peers 1-10 of 12"));
+    assert!(
+        text.contains("@alice00 (0) 10.0.0.2 on default, created 1970-01-01, handshake 10s ago")
+    );
+    let (text, _) = render(&peers, &handshakes, &query, 1010);
+    assert!(text.starts_with("Peers 1-1 of 1"));
+    let (text, pages) = render(&peers, &handshakes, &ListQuery::parse("never"), 1010);
+    assert!(pages == 1 && text.starts_with("Peers 1-10 of 10"));
+    let (text, pages) = render(&peers, &handshakes, &ListQuery::parse("bob"), 1010);
+    assert!(pages == 0 && text == "No peers found");
+    let state = PeerState {
+        public_key: "key0".to_string(),
+        allowed_ips: vec!["10.0.0.2/32".to_string()],
+        endpoint: Some("203.0.113.7:41234".to_string()),
+        last_handshake: Some(1000),
+        rx_bytes: 1536,
+        tx_bytes: 100,
+    };
+    assert!(
+        status(&peers[0], Some(&state), 1130)
+            == "@alice00 on default (10.0.0.2): handshake 2m ago, endpoint 203.0.113.7:41234, received 1.5 KiB, sent 100 B"
+    );
+    assert!(status(&peers[1], None, 1130).ends_with("not on the interface"));
+}
@@ -1,13 +1,27 @@
-use crate::bot::{admin_handle, user_handle, AdminCommands, UserCommands};
+use crate::admin::Admins;
+use crate::bot::{
+    admin_filter, admin_handle, callback_handle, user_handle, AdminCommands, UserCommands,
+};
+use crate::crypto::Keyring;
 use crate::mongo::Mongo;
-use clap::{arg, command, Parser};
+use crate::server::Servers;
+use clap::Parser;
 use configparser::ini::Ini;
-use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use teloxide::{prelude::*, utils::command::BotCommands};
 use tokio::sync::Mutex;
+mod admin;
+mod backend;
 mod bot;
+mod crypto;
+mod list;
 mod mongo;
+mod peers_file;
+mod pool;
+mod reconcile;
+mod registration;
+mod server;
 mod wireguard;
 
 #[tokio::main]
@@ -37,25 +51,128 @@ async fn main() {
         .await
         .get("Mongo", "Table")
         .expect("Cannot find db table");
-    let mongo = Mongo::new(url, name, table).await;
+    let mut mongo = Mongo::new(url, name, table).await;
+    let wg_path = config
+        .lock()
+        .await
+        .get("WireGuard", "WgPath")
+        .unwrap_or("/usr/bin/wg".to_string());
+    let backend = config
+        .lock()
+        .await
+        .get("WireGuard", "Backend")
+        .unwrap_or("auto".to_string());
+    let backend = backend::connect(&backend, &wg_path).expect("Cannot set up WireGuard backend");
+    let servers =
+        Servers::from_config(&*config.lock().await, backend).expect("Cannot read servers");
+    mongo
+        .assign_server(&servers.default_server().name)
+        .await
+        .expect("Cannot migrate peers");
+    // Keys of removed duplicates would still be let in by the interfaces
+    let duplicates = mongo
+        .remove_duplicates()
+        .await
+        .expect("Cannot remove duplicate peers");
+    for (server, public_key) in duplicates {
+        if let Some(server) = servers.find(&server) {
+            if let Err(why) = server.backend.remove_peer(&server.interface, &public_key) {
+                log::error!("Cannot remove duplicate peer {}: {}", public_key, why);
+            }
+        }
+    }
+    mongo
+        .create_indexes()
+        .await
+        .expect("Cannot create db indexes");
+    // Secrets are read as peers, so the older documents are migrated first
+    let private_keys = config
+        .lock()
+        .await
+        .getbool("Mongo", "StorePrivateKeys")
+        .expect("Cannot parse StorePrivateKeys")
+        .unwrap_or(true);
+    if !private_keys {
+        mongo = mongo.without_private_keys();
+        mongo
+            .strip_private_keys()
+            .await
+            .expect("Cannot strip private keys");
+    }
+    // Preshared keys are stored either way, so the keys are used without private keys too
+    if let Some(keyring) =
+        Keyring::load(&*config.lock().await).expect("Cannot load encryption keys")
+    {
+        mongo = mongo.with_keyring(keyring);
+        mongo
+            .reseal_secrets()
+            .await
+            .expect("Cannot encrypt secrets");
+    }
+    let admins =
+        Admins::from_config(&*config.lock().await, mongo.clone()).expect("Cannot read admins");
+    let remove_unknown = config
+        .lock()
+        .await
+        .getbool("WireGuard", "RemoveUnknown")
+        .expect("Cannot parse RemoveUnknown")
+        .unwrap_or(false);
     let bot = Bot::from_env();
-    let chats: Arc<Mutex<HashMap<UserId, ChatId>>> = Arc::new(Mutex::new(HashMap::new()));
+    // Interfaces come up empty after a reboot, while the db still has every peer
+    let report = reconcile::reconcile(&mongo, &servers, true, remove_unknown).await;
+    if !report.is_empty() {
+        log::warn!("Interfaces differ from the db:\n{}", report);
+        admins
+            .notify(&bot, &format!("Startup check:\n{}", report), None)
+            .await;
+    }
+    for server in servers.iter() {
+        peers_file::sync(&mongo, &servers, server).await;
+    }
     bot.set_my_commands(UserCommands::bot_commands())
         .await
         .unwrap();
-    let handler = Update::filter_message()
+    let handler = dptree::entry()
         .branch(
-            dptree::entry()
-                .filter_command::<UserCommands>()
-                .endpoint(user_handle),
+            Update::filter_message()
+                .branch(
+                    dptree::entry()
+                        .filter_command::<UserCommands>()
+                        .endpoint(user_handle),
+                )
+                .branch(
+                    dptree::entry()
+                        .filter_command::<AdminCommands>()
+                        .filter_async(admin_filter)
+                        .endpoint(admin_handle),
+                ),
         )
-        .branch(
-            dptree::entry()
-                .filter_command::<AdminCommands>()
-                .endpoint(admin_handle),
-        );
+        .branch(Update::filter_callback_query().endpoint(callback_handle));
+    let interval = config
+        .lock()
+        .await
+        .getuint("WireGuard", "CheckInterval")
+        .expect("Cannot parse CheckInterval")
+        .unwrap_or(300);
+    let heal = config
+        .lock()
+        .await
+        .getbool("WireGuard", "AutoHeal")
+        .expect("Cannot parse AutoHeal")
+        .unwrap_or(false);
+    if interval > 0 {
+        tokio::spawn(reconcile::watch(
+            bot.clone(),
+            mongo.clone(),
+            servers.clone(),
+            admins.clone(),
+            Duration::from_secs(interval),
+            heal,
+            remove_unknown,
+        ));
+    }
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![mongo, chats, config])
+        .dependencies(dptree::deps![mongo, config, servers, admins])
         .build()
         .dispatch()
         .await;
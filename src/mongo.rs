@@ -1,20 +1,128 @@
+use crate::admin::{AuditEntry, Grant};
+use crate::crypto::Keyring;
+use crate::registration::{Notification, Registration, Status};
 use crate::wireguard::Peer;
 use futures::stream::TryStreamExt;
-use mongodb::{bson::doc, Client};
+use mongodb::error::{Error, ErrorKind, WriteFailure};
+use mongodb::options::{IndexOptions, ReplaceOptions};
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    Client, IndexModel,
+};
 use simple_error::{SimpleError, SimpleResult};
+use std::borrow::Cow;
+use std::sync::Arc;
+
+const REGISTRATIONS: &str = "registrations";
+const AUDIT: &str = "audit";
+const ROLES: &str = "roles";
+const NOTIFICATIONS: &str = "notifications";
+/// Name of the unique index of public keys per server
+const KEY_INDEX: &str = "server_public_key";
+
+/// Outcome of storing a peer with new addresses or a new key
+#[derive(Debug, PartialEq, Eq)]
+pub enum Claim {
+    Stored,
+    /// Another peer of the server has one of the addresses
+    AddressTaken,
+    /// Another peer of the server has the public key
+    KeyTaken,
+}
+
 #[derive(Clone)]
 pub struct Mongo {
     name: String,
     table: String,
     client: Client,
+    /// Peers are written with their private keys
+    private_keys: bool,
+    /// Private keys are encrypted with it, if it is set
+    keyring: Option<Arc<Keyring>>,
 }
 
 impl Mongo {
     pub async fn new(url: &str, name: String, table: String) -> Self {
         Mongo {
-            name: name,
-            table: table,
+            name,
+            table,
             client: Client::with_uri_str(url).await.unwrap(),
+            private_keys: true,
+            keyring: None,
+        }
+    }
+
+    /// Encrypts private keys before they are written and decrypts them after they are read
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(Arc::new(keyring));
+        self
+    }
+
+    /// Private keys are dropped before peers are written, so they live only as long as a request
+    pub fn without_private_keys(mut self) -> Self {
+        self.private_keys = false;
+        self
+    }
+
+    /// The peer as it is written: without the private key, secrets sealed if there are keys
+    fn stored<'a>(&self, peer: &'a Peer) -> SimpleResult<Cow<'a, Peer>> {
+        if self.keyring.is_none() && (self.private_keys || peer.private_key.is_none()) {
+            return Ok(Cow::Borrowed(peer));
+        }
+        let private_key = match self.private_keys {
+            true => self.seal(&peer.private_key, peer, "private_key")?,
+            false => None,
+        };
+        Ok(Cow::Owned(Peer {
+            private_key,
+            preshared_key: self.seal(&peer.preshared_key, peer, "preshared_key")?,
+            ..peer.clone()
+        }))
+    }
+
+    /// The peer as it was read
+    fn opened(&self, mut peer: Peer) -> Peer {
+        let private_key = peer.private_key.take();
+        peer.private_key = self.open(private_key, &peer, "private_key");
+        let preshared_key = peer.preshared_key.take();
+        peer.preshared_key = self.open(preshared_key, &peer, "preshared_key");
+        peer
+    }
+
+    fn seal(
+        &self,
+        secret: &Option<String>,
+        peer: &Peer,
+        field: &str,
+    ) -> SimpleResult<Option<String>> {
+        match (secret, &self.keyring) {
+            (Some(secret), Some(keyring)) => {
+                Ok(Some(keyring.seal(secret, &secret_context(peer, field))?))
+            }
+            (secret, _) => Ok(secret.clone()),
+        }
+    }
+
+    /// A secret that cannot be decrypted is dropped, so the user gets new keys with the next config
+    fn open(&self, secret: Option<String>, peer: &Peer, field: &str) -> Option<String> {
+        let secret = secret?;
+        match &self.keyring {
+            Some(keyring) => match keyring.open(&secret, &secret_context(peer, field)) {
+                Err(why) => {
+                    log::error!("Cannot open {} of {}: {}", field, peer.username, why);
+                    None
+                }
+                Ok(secret) => Some(secret),
+            },
+            None if Keyring::is_sealed(&secret) => {
+                log::error!(
+                    "{} of {} is encrypted, but there are no keys",
+                    field,
+                    peer.username
+                );
+                None
+            }
+            None => Some(secret),
         }
     }
 
@@ -23,7 +131,7 @@ impl Mongo {
             .client
             .database(&self.name)
             .collection::<Peer>(&self.table);
-        match peers.insert_one(peer, None).await {
+        match peers.insert_one(self.stored(peer)?.as_ref(), None).await {
             Err(why) => {
                 log::error!("Cannot add peer to db {}", why.to_string());
                 Err(SimpleError::from(why))
@@ -32,22 +140,51 @@ impl Mongo {
         }
     }
 
+    /// Replaces the peer of the user on its server in one write, or adds it if there is none
     pub async fn update(&self, peer: &Peer) -> SimpleResult<()> {
-        match self.delete(&peer).await {
+        match self.replace(self.stored(peer)?.as_ref()).await {
             Err(why) => {
                 log::error!("Cannot update peer {}", why.to_string());
-                return Err(why);
+                Err(SimpleError::from(why))
             }
-            Ok(_) => match self.add(&peer).await {
-                Err(why) => {
-                    log::error!("Cannot update peer {}", why.to_string());
-                    return Err(why);
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// Stores the peer with its new addresses and key,
+    /// unless another peer of the same server holds one of them already
+    pub async fn claim_ip(&self, peer: &Peer) -> SimpleResult<Claim> {
+        match self.replace(self.stored(peer)?.as_ref()).await {
+            Err(why) => match duplicate_index(&why) {
+                Some(index) if index.contains(KEY_INDEX) => Ok(Claim::KeyTaken),
+                Some(_) => Ok(Claim::AddressTaken),
+                None => {
+                    log::error!("Cannot claim ip {}", why.to_string());
+                    Err(SimpleError::from(why))
                 }
-                Ok(_) => Ok(()),
             },
+            Ok(_) => Ok(Claim::Stored),
         }
     }
 
+    async fn replace(&self, stored: &Peer) -> Result<(), Error> {
+        let peers = self
+            .client
+            .database(&self.name)
+            .collection::<Peer>(&self.table);
+        peers
+            .replace_one(
+                doc! {
+                    "user_id": stored.user_id as i64,
+                    "server": &stored.server
+                },
+                stored,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map(|_| ())
+    }
+
     pub async fn find_by_id(&self, id: u64) -> Option<Peer> {
         let peers = self
             .client
@@ -62,7 +199,7 @@ impl Mongo {
             )
             .await
         {
-            Ok(result) => result,
+            Ok(result) => result.map(|peer| self.opened(peer)),
             Err(err) => {
                 println!("{}", err);
                 None
@@ -70,6 +207,103 @@ impl Mongo {
         }
     }
 
+    pub async fn find_by_username(&self, username: &str) -> Option<Peer> {
+        let peers = self
+            .client
+            .database(&self.name)
+            .collection::<Peer>(&self.table);
+        match peers
+            .find_one(
+                doc! {
+                    "username": username
+                },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result.map(|peer| self.opened(peer)),
+            Err(err) => {
+                log::error!("Cannot find peer {}", err);
+                None
+            }
+        }
+    }
+
+    pub async fn find(&self, id: u64, server: &str) -> Option<Peer> {
+        let peers = self
+            .client
+            .database(&self.name)
+            .collection::<Peer>(&self.table);
+        match peers
+            .find_one(
+                doc! {
+                    "user_id": id as i64,
+                    "server": server
+                },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result.map(|peer| self.opened(peer)),
+            Err(err) => {
+                log::error!("Cannot find peer {}", err);
+                None
+            }
+        }
+    }
+
+    /// Peers with the public key, on any server
+    pub async fn find_by_key(&self, public_key: &str) -> SimpleResult<Vec<Peer>> {
+        let peers = self
+            .client
+            .database(&self.name)
+            .collection::<Peer>(&self.table);
+        match peers
+            .find(
+                doc! {
+                    "public_key": public_key
+                },
+                None,
+            )
+            .await
+        {
+            Err(why) => Err(SimpleError::from(why)),
+            Ok(cursor) => match cursor.try_collect::<Vec<Peer>>().await {
+                Err(why) => Err(SimpleError::from(why)),
+                Ok(found) => Ok(found.into_iter().map(|peer| self.opened(peer)).collect()),
+            },
+        }
+    }
+
+    /// Peers of the user on every server
+    pub async fn find_all_by_id(&self, id: u64) -> Vec<Peer> {
+        let peers = self
+            .client
+            .database(&self.name)
+            .collection::<Peer>(&self.table);
+        match peers
+            .find(
+                doc! {
+                    "user_id": id as i64
+                },
+                None,
+            )
+            .await
+        {
+            Ok(cursor) => cursor
+                .try_collect::<Vec<Peer>>()
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .map(|peer| self.opened(peer))
+                .collect(),
+            Err(err) => {
+                log::error!("Cannot find peers {}", err);
+                vec![]
+            }
+        }
+    }
+
     pub async fn delete(&self, peer: &Peer) -> SimpleResult<()> {
         let peers = self
             .client
@@ -78,7 +312,8 @@ impl Mongo {
         match peers
             .delete_one(
                 doc! {
-                    "user_id": peer.user_id as i64
+                    "user_id": peer.user_id as i64,
+                    "server": &peer.server
                 },
                 None,
             )
@@ -86,11 +321,507 @@ impl Mongo {
         {
             Err(why) => {
                 log::error!("Cannot delete peer from db {}", why.to_string());
+                Err(SimpleError::from(why))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// Unique addresses per server make concurrent allocations of the same address fail,
+    /// a unique user per server keeps a second registration from adding another peer,
+    /// a unique key per server keeps a user from taking over the peer of another one,
+    /// servers sharing an interface are checked against the interface before a key is stored
+    pub async fn create_indexes(&self) -> SimpleResult<()> {
+        let peers = self
+            .client
+            .database(&self.name)
+            .collection::<Peer>(&self.table);
+        let mut indexes: Vec<IndexModel> = ["ip", "ip6"]
+            .map(|field| {
+                IndexModel::builder()
+                    .keys(doc! { "server": 1, field: 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .unique(true)
+                            .partial_filter_expression(doc! { field: { "$type": "string" } })
+                            .build(),
+                    )
+                    .build()
+            })
+            .into();
+        indexes.push(
+            IndexModel::builder()
+                .keys(doc! { "user_id": 1, "server": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+        );
+        indexes.push(
+            IndexModel::builder()
+                .keys(doc! { "server": 1, "public_key": 1 })
+                .options(
+                    IndexOptions::builder()
+                        .name(KEY_INDEX.to_string())
+                        .unique(true)
+                        .partial_filter_expression(doc! { "public_key": { "$type": "string" } })
+                        .build(),
+                )
+                .build(),
+        );
+        if let Err(why) = peers.create_indexes(indexes, None).await {
+            log::error!("Cannot create indexes {}", why.to_string());
+            return Err(SimpleError::from(why));
+        }
+        let registrations = self
+            .client
+            .database(&self.name)
+            .collection::<Registration>(REGISTRATIONS);
+        let index = IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        if let Err(why) = registrations.create_index(index, None).await {
+            log::error!("Cannot create indexes {}", why.to_string());
+            return Err(SimpleError::from(why));
+        }
+        let roles = self.client.database(&self.name).collection::<Grant>(ROLES);
+        let index = IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        if let Err(why) = roles.create_index(index, None).await {
+            log::error!("Cannot create indexes {}", why.to_string());
+            return Err(SimpleError::from(why));
+        }
+        let notifications = self
+            .client
+            .database(&self.name)
+            .collection::<Notification>(NOTIFICATIONS);
+        let index = IndexModel::builder()
+            .keys(doc! { "chat_id": 1, "message_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        match notifications.create_index(index, None).await {
+            Err(why) => {
+                log::error!("Cannot create indexes {}", why.to_string());
+                Err(SimpleError::from(why))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// Older versions could add a user twice to a server, only one peer is kept,
+    /// so the unique index can be created. Configured peers win over ones without keys,
+    /// then the last written one, older versions kept the approval date on rewrites.
+    /// Returns the servers and public keys of the removed peers, to take them off the interfaces.
+    pub async fn remove_duplicates(&self) -> SimpleResult<Vec<(String, String)>> {
+        let peers = self
+            .client
+            .database(&self.name)
+            .collection::<Document>(&self.table);
+        let pipeline = [
+            doc! { "$addFields": {
+                "configured": { "$eq": [{ "$type": "$public_key" }, "string"] },
+            } },
+            doc! { "$sort": { "configured": -1, "_id": -1 } },
+            doc! { "$group": {
+                "_id": { "user_id": "$user_id", "server": "$server" },
+                "peers": { "$push": { "id": "$_id", "public_key": "$public_key" } },
+            } },
+            doc! { "$match": { "peers.1": { "$exists": true } } },
+        ];
+        let duplicates: Vec<Document> = match peers.aggregate(pipeline, None).await {
+            Err(why) => return Err(SimpleError::from(why)),
+            Ok(cursor) => match cursor.try_collect().await {
+                Err(why) => return Err(SimpleError::from(why)),
+                Ok(duplicates) => duplicates,
+            },
+        };
+        let mut removed = vec![];
+        for duplicate in duplicates {
+            let group = match duplicate.get_document("_id") {
+                Ok(group) => group,
+                Err(why) => return Err(SimpleError::from(why)),
+            };
+            let server = group.get_str("server").unwrap_or_default().to_string();
+            let stored: Vec<&Document> = match duplicate.get_array("peers") {
+                Ok(stored) => stored
+                    .iter()
+                    .filter_map(|peer| peer.as_document())
+                    .collect(),
+                Err(why) => return Err(SimpleError::from(why)),
+            };
+            let kept = stored[0].get_str("public_key").ok();
+            let ids: Vec<_> = stored[1..]
+                .iter()
+                .filter_map(|peer| peer.get("id"))
+                .collect();
+            if let Err(why) = peers
+                .delete_many(doc! { "_id": { "$in": &ids } }, None)
+                .await
+            {
+                log::error!("Cannot remove duplicate peers {}", why.to_string());
                 return Err(SimpleError::from(why));
             }
+            log::warn!(
+                "Removed {} duplicate peers of {}, only one is kept",
+                ids.len(),
+                group
+            );
+            removed.extend(
+                stored[1..]
+                    .iter()
+                    .filter_map(|peer| peer.get_str("public_key").ok())
+                    .filter(|key| Some(*key) != kept)
+                    .map(|key| (server.clone(), key.to_string())),
+            );
+        }
+        Ok(removed)
+    }
+
+    /// Stores a new request of the user, replacing the previous one
+    pub async fn add_registration(&self, registration: &Registration) -> SimpleResult<()> {
+        let registrations = self
+            .client
+            .database(&self.name)
+            .collection::<Registration>(REGISTRATIONS);
+        match registrations
+            .replace_one(
+                doc! {
+                    "user_id": registration.user_id as i64
+                },
+                registration,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await
+        {
+            Err(why) => {
+                log::error!("Cannot add registration to db {}", why.to_string());
+                Err(SimpleError::from(why))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    pub async fn find_registration(&self, id: u64) -> Option<Registration> {
+        let registrations = self
+            .client
+            .database(&self.name)
+            .collection::<Registration>(REGISTRATIONS);
+        match registrations
+            .find_one(
+                doc! {
+                    "user_id": id as i64
+                },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(err) => {
+                log::error!("Cannot find registration {}", err);
+                None
+            }
+        }
+    }
+
+    pub async fn find_registration_by_username(&self, username: &str) -> Option<Registration> {
+        let registrations = self
+            .client
+            .database(&self.name)
+            .collection::<Registration>(REGISTRATIONS);
+        match registrations
+            .find_one(
+                doc! {
+                    "username": username
+                },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(err) => {
+                log::error!("Cannot find registration {}", err);
+                None
+            }
+        }
+    }
+
+    /// Returns false if there is no registration of the user
+    pub async fn set_registration_status(&self, id: u64, status: Status) -> SimpleResult<bool> {
+        let registrations = self
+            .client
+            .database(&self.name)
+            .collection::<Registration>(REGISTRATIONS);
+        let status = mongodb::bson::to_bson(&status).unwrap();
+        match registrations
+            .update_one(
+                doc! {
+                    "user_id": id as i64
+                },
+                doc! {
+                    "$set": { "status": status, "updated": DateTime::now() }
+                },
+                None,
+            )
+            .await
+        {
+            Err(why) => {
+                log::error!("Cannot update registration {}", why.to_string());
+                Err(SimpleError::from(why))
+            }
+            Ok(result) => Ok(result.matched_count > 0),
+        }
+    }
+
+    pub async fn add_audit(&self, entry: &AuditEntry) -> SimpleResult<()> {
+        let audit = self
+            .client
+            .database(&self.name)
+            .collection::<AuditEntry>(AUDIT);
+        match audit.insert_one(entry, None).await {
+            Err(why) => {
+                log::error!("Cannot add audit entry to db {}", why.to_string());
+                Err(SimpleError::from(why))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    pub async fn add_notification(&self, notification: &Notification) -> SimpleResult<()> {
+        let notifications = self
+            .client
+            .database(&self.name)
+            .collection::<Notification>(NOTIFICATIONS);
+        match notifications.insert_one(notification, None).await {
+            Err(why) => {
+                log::error!("Cannot add notification to db {}", why.to_string());
+                Err(SimpleError::from(why))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    pub async fn find_notification(&self, chat_id: i64, message_id: i32) -> Option<Notification> {
+        let notifications = self
+            .client
+            .database(&self.name)
+            .collection::<Notification>(NOTIFICATIONS);
+        match notifications
+            .find_one(
+                doc! {
+                    "chat_id": chat_id,
+                    "message_id": message_id
+                },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(err) => {
+                log::error!("Cannot find notification {}", err);
+                None
+            }
+        }
+    }
+
+    /// Gives the user a role, replacing the previous one
+    pub async fn add_grant(&self, grant: &Grant) -> SimpleResult<()> {
+        let roles = self.client.database(&self.name).collection::<Grant>(ROLES);
+        match roles
+            .replace_one(
+                doc! {
+                    "user_id": grant.user_id as i64
+                },
+                grant,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await
+        {
+            Err(why) => {
+                log::error!("Cannot add role to db {}", why.to_string());
+                Err(SimpleError::from(why))
+            }
             Ok(_) => Ok(()),
         }
     }
+
+    pub async fn find_grant(&self, id: u64) -> Option<Grant> {
+        let roles = self.client.database(&self.name).collection::<Grant>(ROLES);
+        match roles
+            .find_one(
+                doc! {
+                    "user_id": id as i64
+                },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(err) => {
+                log::error!("Cannot find role {}", err);
+                None
+            }
+        }
+    }
+
+    pub async fn get_grants(&self) -> Vec<Grant> {
+        let roles = self.client.database(&self.name).collection::<Grant>(ROLES);
+        match roles.find(None, None).await {
+            Ok(cursor) => cursor.try_collect().await.unwrap_or_default(),
+            Err(err) => {
+                log::error!("Cannot get roles {}", err);
+                vec![]
+            }
+        }
+    }
+
+    /// Returns false if the user has no role
+    pub async fn delete_grant(&self, id: u64) -> SimpleResult<bool> {
+        let roles = self.client.database(&self.name).collection::<Grant>(ROLES);
+        match roles
+            .delete_one(
+                doc! {
+                    "user_id": id as i64
+                },
+                None,
+            )
+            .await
+        {
+            Err(why) => {
+                log::error!("Cannot delete role {}", why.to_string());
+                Err(SimpleError::from(why))
+            }
+            Ok(result) => Ok(result.deleted_count > 0),
+        }
+    }
+
+    /// Seals secrets that are plaintext or sealed with an older key with the current one
+    pub async fn reseal_secrets(&self) -> SimpleResult<()> {
+        let keyring = match &self.keyring {
+            Some(keyring) => keyring,
+            None => return Ok(()),
+        };
+        let peers = self
+            .client
+            .database(&self.name)
+            .collection::<Peer>(&self.table);
+        for field in ["private_key", "preshared_key"] {
+            let stored: Vec<Peer> = match peers
+                .find(doc! { field: { "$type": "string" } }, None)
+                .await
+            {
+                Err(why) => return Err(SimpleError::from(why)),
+                Ok(cursor) => match cursor.try_collect().await {
+                    Err(why) => return Err(SimpleError::from(why)),
+                    Ok(stored) => stored,
+                },
+            };
+            let mut resealed = 0;
+            for peer in stored {
+                let secret = match field {
+                    "private_key" => peer.private_key.clone(),
+                    _ => peer.preshared_key.clone(),
+                };
+                if secret
+                    .as_deref()
+                    .is_none_or(|secret| keyring.is_current(secret))
+                {
+                    continue;
+                }
+                let sealed = match self.open(secret, &peer, field) {
+                    Some(secret) => keyring.seal(&secret, &secret_context(&peer, field))?,
+                    None => continue, // Cannot be opened, it is replaced with the next config
+                };
+                if let Err(why) = peers
+                    .update_one(
+                        doc! {
+                            "user_id": peer.user_id as i64,
+                            "server": &peer.server
+                        },
+                        doc! { "$set": { field: sealed } },
+                        None,
+                    )
+                    .await
+                {
+                    log::error!("Cannot reseal {} {}", field, why.to_string());
+                    return Err(SimpleError::from(why));
+                }
+                resealed += 1;
+            }
+            if resealed > 0 {
+                log::info!(
+                    "Sealed {} of {} peers with the current key",
+                    field,
+                    resealed
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes private keys written while they were stored
+    pub async fn strip_private_keys(&self) -> SimpleResult<()> {
+        let peers = self
+            .client
+            .database(&self.name)
+            .collection::<Peer>(&self.table);
+        match peers
+            .update_many(
+                doc! { "private_key": { "$exists": true } },
+                doc! { "$unset": { "private_key": "" } },
+                None,
+            )
+            .await
+        {
+            Err(why) => {
+                log::error!("Cannot strip private keys {}", why.to_string());
+                Err(SimpleError::from(why))
+            }
+            Ok(result) => {
+                if result.modified_count > 0 {
+                    log::info!("Stripped private keys of {} peers", result.modified_count);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Peers created before multi-server support belong to the default server
+    pub async fn assign_server(&self, server: &str) -> SimpleResult<()> {
+        let peers = self
+            .client
+            .database(&self.name)
+            .collection::<Peer>(&self.table);
+        match peers
+            .update_many(
+                doc! { "server": { "$exists": false } },
+                doc! { "$set": { "server": server } },
+                None,
+            )
+            .await
+        {
+            Err(why) => {
+                log::error!("Cannot assign server to peers {}", why.to_string());
+                Err(SimpleError::from(why))
+            }
+            Ok(result) => {
+                if result.modified_count > 0 {
+                    log::info!("Assigned {} peers to {}", result.modified_count, server);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub async fn drop(&self) {
+        let peers = self
+            .client
+            .database(&self.name)
+            .collection::<Peer>(&self.table);
+        peers.drop(None).await.unwrap()
+    }
+
     #[cfg(test)]
     pub async fn count(&self) -> u64 {
         let peers = self
@@ -100,18 +831,40 @@ impl Mongo {
         peers.count_documents(None, None).await.unwrap()
     }
 
-    pub async fn get_peers(&self) -> Vec<Peer> {
+    pub async fn get_peers(&self) -> SimpleResult<Vec<Peer>> {
         let peers = self
             .client
             .database(&self.name)
             .collection::<Peer>(&self.table);
-        peers
-            .find(None, None)
-            .await
-            .unwrap()
-            .try_collect()
-            .await
-            .unwrap()
+        let stored: Vec<Peer> = match peers.find(None, None).await {
+            Err(why) => {
+                log::error!("Cannot read peers {}", why.to_string());
+                return Err(SimpleError::from(why));
+            }
+            Ok(cursor) => match cursor.try_collect().await {
+                Err(why) => {
+                    log::error!("Cannot read peers {}", why.to_string());
+                    return Err(SimpleError::from(why));
+                }
+                Ok(stored) => stored,
+            },
+        };
+        Ok(stored.into_iter().map(|peer| self.opened(peer)).collect())
+    }
+}
+
+/// Ties a sealed secret to the peer and the field it belongs to
+fn secret_context(peer: &Peer, field: &str) -> String {
+    format!("{}:{}:{}", peer.user_id, peer.server, field)
+}
+
+/// Message of a duplicate key error, it names the index
+fn duplicate_index(why: &Error) -> Option<&str> {
+    match why.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(error)) if error.code == 11000 => {
+            Some(&error.message)
+        }
+        _ => None,
     }
 }
 
@@ -125,33 +878,192 @@ async fn test_db() {
         "peers".to_string(),
     )
     .await;
+    mongo.create_indexes().await.unwrap();
     let peer1 = Peer {
         user_id: 256,
         username: "User1".to_string(),
+        server: "default".to_string(),
         public_key: None,
         private_key: None,
+        preshared_key: None,
         ip: None,
+        ip6: None,
         date: mongodb::bson::DateTime::now(),
     };
     let peer2 = Peer {
         user_id: 256,
         username: "User2".to_string(),
+        server: "default".to_string(),
         public_key: None,
         private_key: None,
+        preshared_key: None,
         ip: Some(Ipv4Addr::new(234, 32, 32, 234)),
+        ip6: None,
         date: mongodb::bson::DateTime::now(),
     };
     let count = mongo.count().await;
     mongo.add(&peer1).await.unwrap();
+    assert!(mongo.add(&peer1).await.is_err());
     mongo.update(&peer2).await.unwrap();
-    let peers = mongo.get_peers().await;
+    let peers = mongo.get_peers().await.unwrap();
     assert!(peers.len() as u64 == count + 1);
-    let peer = mongo.find_by_id(256).await;
-    if let Some(peer) = peer {
-        assert!(peer.username == "User2");
-        mongo.delete(&peer).await.unwrap();
-        assert!(mongo.find_by_id(256).await.is_none())
-    } else {
-        assert!(false);
-    }
+    let peer = mongo
+        .find_by_id(256)
+        .await
+        .expect("Cannot find updated peer");
+    assert!(peer.username == "User2");
+    mongo.delete(&peer).await.unwrap();
+    assert!(mongo.find_by_id(256).await.is_none());
+    // Duplicates written before the unique index existed, the peer rewritten by /getconfig
+    // is the last one written, but it kept the older approval date
+    let duplicates = Mongo::new(
+        "mongodb://localhost:27017",
+        "gimmewire".to_string(),
+        "duplicates".to_string(),
+    )
+    .await;
+    duplicates.drop().await;
+    let stale = Peer {
+        public_key: Some("stale".to_string()),
+        ip: Some(Ipv4Addr::new(234, 32, 32, 235)),
+        ..peer1.clone()
+    };
+    let live = Peer {
+        username: "Live".to_string(),
+        public_key: Some("live".to_string()),
+        date: mongodb::bson::DateTime::from_millis(1000),
+        ..peer2.clone()
+    };
+    duplicates.add(&stale).await.unwrap();
+    duplicates.add(&peer1).await.unwrap();
+    duplicates.add(&live).await.unwrap();
+    let removed = duplicates.remove_duplicates().await.unwrap();
+    assert!(removed == vec![("default".to_string(), "stale".to_string())]);
+    duplicates.create_indexes().await.unwrap();
+    assert!(duplicates.count().await == 1);
+    assert!(duplicates.find_by_id(256).await.unwrap().username == "Live");
+    duplicates.drop().await;
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_registrations() {
+    let mongo = Mongo::new(
+        "mongodb://localhost:27017",
+        "gimmewire".to_string(),
+        "peers".to_string(),
+    )
+    .await;
+    let registration = Registration {
+        user_id: 512,
+        username: "User".to_string(),
+        chat_id: 1024,
+        status: Status::Pending,
+        created: DateTime::now(),
+        updated: DateTime::now(),
+    };
+    mongo.add_registration(&registration).await.unwrap();
+    assert!(mongo
+        .set_registration_status(512, Status::Approved)
+        .await
+        .unwrap());
+    let registration = mongo.find_registration(512).await.unwrap();
+    assert!(registration.status == Status::Approved && registration.chat_id == 1024);
+    assert!(!mongo
+        .set_registration_status(513, Status::Rejected)
+        .await
+        .unwrap());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_private_keys() {
+    let mongo = Mongo::new(
+        "mongodb://localhost:27017",
+        "gimmewire".to_string(),
+        "peers".to_string(),
+    )
+    .await;
+    let peer = Peer {
+        user_id: 1024,
+        username: "User".to_string(),
+        server: "default".to_string(),
+        public_key: Some("public".to_string()),
+        private_key: Some("private".to_string()),
+        preshared_key: Some("preshared".to_string()),
+        ip: None,
+        ip6: None,
+        date: DateTime::now(),
+    };
+    assert!(mongo.stored(&peer).unwrap().private_key == peer.private_key);
+    let keyring = Keyring::parse("k1:AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=").unwrap();
+    let sealed = mongo.clone().with_keyring(keyring);
+    let stored = sealed.stored(&peer).unwrap().into_owned();
+    assert!(stored.private_key.as_ref().unwrap().starts_with("aead:k1:"));
+    assert!(stored
+        .preshared_key
+        .as_ref()
+        .unwrap()
+        .starts_with("aead:k1:"));
+    let opened = sealed.opened(stored.clone());
+    assert!(opened.private_key == peer.private_key && opened.preshared_key == peer.preshared_key);
+    // Without the keys a sealed private key is dropped instead of used as a key
+    assert!(mongo.opened(stored).private_key.is_none());
+    let mongo = mongo.without_private_keys();
+    let stored = mongo.stored(&peer).unwrap();
+    assert!(stored.private_key.is_none() && stored.public_key == peer.public_key);
+    // Preshared keys are kept, the interface cannot be restored without them
+    assert!(stored.preshared_key == peer.preshared_key);
+    let document = mongodb::bson::to_document(stored.as_ref()).unwrap();
+    assert!(!document.contains_key("private_key"));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_reseal_legacy() {
+    use std::net::Ipv4Addr;
+    let mongo = Mongo::new(
+        "mongodb://localhost:27017",
+        "gimmewire".to_string(),
+        "legacy".to_string(),
+    )
+    .await;
+    mongo.drop().await;
+    // Shaped like the first version: no server, address as octets, plaintext private key
+    let legacy = mongo
+        .client
+        .database(&mongo.name)
+        .collection::<Document>(&mongo.table);
+    legacy
+        .insert_one(
+            doc! {
+                "user_id": 512_i64,
+                "username": "User",
+                "public_key": "public",
+                "private_key": "private",
+                "ip": [10, 0, 0, 2],
+                "date": DateTime::now(),
+            },
+            None,
+        )
+        .await
+        .unwrap();
+    let keyring = Keyring::parse("k1:AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=").unwrap();
+    let mongo = mongo.with_keyring(keyring);
+    mongo.assign_server("default").await.unwrap();
+    mongo.create_indexes().await.unwrap();
+    mongo.reseal_secrets().await.unwrap();
+    let stored = legacy
+        .find_one(doc! { "user_id": 512_i64 }, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(stored
+        .get_str("private_key")
+        .unwrap()
+        .starts_with("aead:k1:"));
+    let peer = mongo.find(512, "default").await.unwrap();
+    assert!(peer.private_key.as_deref() == Some("private"));
+    assert!(peer.ip == Some(Ipv4Addr::new(10, 0, 0, 2)));
+    mongo.drop().await;
 }
@@ -0,0 +1,137 @@
+use crate::mongo::Mongo;
+use crate::server::{Server, Servers};
+use crate::wireguard::{self, Peer};
+use ipnet::IpNet;
+use simple_error::{SimpleError, SimpleResult};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+/// Everything after this line is rewritten, everything before it (like `[Interface]`) is kept
+const MARKER: &str = "# Peers below are managed by gimmewire, changes are overwritten";
+
+/// Rewrites the peers file of the server from the db, so `wg-quick up` restores the interface.
+/// A failed write is only logged, the interface itself is already up to date.
+pub async fn sync(mongo: &Mongo, servers: &Servers, server: &Server) {
+    let path = match &server.peers_file {
+        Some(path) => path,
+        None => return,
+    };
+    // Servers sharing the file share the interface too, and the lock of the first of them,
+    // so a write with older peers never replaces a newer one
+    let owner = servers
+        .iter()
+        .find(|server| server.peers_file.as_ref() == Some(path))
+        .unwrap_or(server);
+    let _writing = owner.writing.lock().await;
+    let peers = match mongo.get_peers().await {
+        Err(why) => {
+            log::error!("Cannot write peers file {}: {}", path, why);
+            return;
+        }
+        Ok(peers) => peers,
+    };
+    let peers: Vec<Peer> = peers
+        .into_iter()
+        .filter(|peer| {
+            servers
+                .find(&peer.server)
+                .is_some_and(|server| server.peers_file.as_ref() == Some(path))
+        })
+        .collect();
+    if let Err(why) = write(path, &peers) {
+        log::error!("Cannot write peers file {}: {}", path, why);
+    }
+}
+
+/// Writes a temporary file next to the target and renames it, so a crash never leaves half a file
+fn write(path: &str, peers: &[Peer]) -> SimpleResult<()> {
+    let existing = match fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(why) if why.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(why) => return Err(SimpleError::from(why)),
+    };
+    let tmp = format!("{}.tmp", path);
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600) // wg-quick configs hold the private key of the interface
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(render(&existing, peers).as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if let Err(why) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(SimpleError::from(why));
+    }
+    Ok(())
+}
+
+/// Peers without a config are left out, they are not on the interface either
+fn render(existing: &str, peers: &[Peer]) -> String {
+    let mut content = match existing.find(MARKER) {
+        Some(index) => existing[..index].to_string(),
+        None if existing.trim().is_empty() => String::new(),
+        None => format!("{}\n\n", existing.trim_end()),
+    };
+    content.push_str(MARKER);
+    content.push('\n');
+    let mut peers: Vec<&Peer> = peers
+        .iter()
+        .filter(|peer| peer.public_key.is_some() && peer.ip.is_some())
+        .collect();
+    peers.sort_by_key(|peer| peer.ip);
+    for peer in peers {
+        let allowed_ips: Vec<String> = wireguard::allowed_ips(peer)
+            .iter()
+            .map(|ip| IpNet::from(*ip).to_string())
+            .collect();
+        content.push_str(&format!(
+            "\n# @{} ({})\n[Peer]\nPublicKey = {}\n",
+            peer.username,
+            peer.user_id,
+            peer.public_key.as_ref().unwrap(),
+        ));
+        if let Some(preshared_key) = &peer.preshared_key {
+            content.push_str(&format!("PresharedKey = {}\n", preshared_key));
+        }
+        content.push_str(&format!("AllowedIPs = {}\n", allowed_ips.join(", ")));
+    }
+    content
+}
+
+#[cfg(test)]
+#[test]
+fn render_peers_file() {
+    use mongodb::bson::DateTime;
+    use std::net::Ipv4Addr;
+    let peer = |user_id: u64, ip: Option<Ipv4Addr>| Peer {
+        user_id,
+        username: format!("user{}", user_id),
+        server: "default".to_string(),
+        public_key: Some(format!("key{}", user_id)),
+        private_key: None,
+        preshared_key: None,
+        ip,
+        ip6: None,
+        date: DateTime::now(),
+    };
+    let peers = [
+        peer(2, Some(Ipv4Addr::new(10, 0, 0, 3))),
+        peer(1, Some(Ipv4Addr::new(10, 0, 0, 2))),
+        peer(3, None),
+    ];
+    let interface = "[Interface]\nPrivateKey = secret\nListenPort = 51820\n";
+    let content = render(interface, &peers);
+    assert!(content
+        .starts_with("[Interface]\nPrivateKey = secret\nListenPort = 51820\n\n# Peers below"));
+    assert!(content.ends_with(
+        "\n# @user1 (1)\n[Peer]\nPublicKey = key1\nAllowedIPs = 10.0.0.2/32\n\n# @user2 (2)\n[Peer]\nPublicKey = key2\nAllowedIPs = 10.0.0.3/32\n"
+    ));
+    // Rewriting keeps the interface part and replaces the peers
+    assert!(render(&content, &peers[1..]) == render(interface, &peers[1..]));
+    assert!(render("", &[]) == format!("{}\n", MARKER));
+}
@@ -0,0 +1,276 @@
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use simple_error::{SimpleError, SimpleResult};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Every address of the pool is assigned to a peer.
+#[derive(Debug)]
+pub struct PoolExhausted {
+    pub pool: IpNet,
+    pub used: usize,
+    pub capacity: u128,
+}
+
+impl fmt::Display for PoolExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Address pool {} is exhausted, {} of {} addresses are used",
+            self.pool, self.used, self.capacity
+        )
+    }
+}
+
+impl std::error::Error for PoolExhausted {}
+
+/// Addresses a server hands out to its peers.
+#[derive(Debug, Clone)]
+pub struct AddressPool {
+    pub v4: Ipv4Net,
+    pub v6: Option<Ipv6Net>,
+    /// Never assigned to peers, the server address by default
+    pub reserved: Vec<IpNet>,
+}
+
+impl AddressPool {
+    /// `reserved` is a comma separated list of addresses or CIDRs,
+    /// if it is empty the first address of each pool is reserved for the server.
+    pub fn new(v4: &str, v6: Option<&str>, reserved: Option<&str>) -> SimpleResult<Self> {
+        let v4: Ipv4Net = match v4.trim().parse() {
+            Err(why) => return Err(SimpleError::with("Cannot parse IPv4 pool", why)),
+            Ok(v4) => v4,
+        };
+        let v6: Option<Ipv6Net> = match v6.map(|v6| v6.trim().parse()) {
+            Some(Err(why)) => return Err(SimpleError::with("Cannot parse IPv6 pool", why)),
+            Some(Ok(v6)) => Some(v6),
+            None => None,
+        };
+        let reserved = match reserved {
+            Some(reserved) => reserved
+                .split(',')
+                .map(|net| parse_net(net.trim()))
+                .collect::<SimpleResult<Vec<IpNet>>>()?,
+            None => {
+                let mut reserved = vec![IpNet::from(IpAddr::V4(v4.hosts().next().unwrap()))];
+                if let Some(v6) = v6 {
+                    reserved.push(IpNet::from(IpAddr::V6(first_v6(&v6))));
+                }
+                reserved
+            }
+        };
+        Ok(AddressPool { v4, v6, reserved })
+    }
+
+    /// Lowest free addresses of both pools, IPv6 one only if the pool is configured.
+    /// Preferred addresses are kept, while they are free and still belong to the pool.
+    pub fn allocate(
+        &self,
+        used: &HashSet<Ipv4Addr>,
+        used6: &HashSet<Ipv6Addr>,
+        preferred: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+    ) -> Result<(Ipv4Addr, Option<Ipv6Addr>), PoolExhausted> {
+        let preferred4 = preferred.0.filter(|ip| {
+            let hosts = self.v4.prefix_len() >= 31
+                || (*ip != self.v4.network() && *ip != self.v4.broadcast());
+            self.v4.contains(ip)
+                && hosts
+                && !used.contains(ip)
+                && !self.is_reserved(IpAddr::V4(*ip))
+        });
+        let preferred6 = preferred.1.filter(|ip| match self.v6 {
+            Some(v6) => {
+                v6.contains(ip)
+                    && *ip != v6.network()
+                    && !used6.contains(ip)
+                    && !self.is_reserved(IpAddr::V6(*ip))
+            }
+            None => false,
+        });
+        let ip = match preferred4.or_else(|| self.next_v4(used)) {
+            None => {
+                let used = used.iter().map(|ip| IpAddr::V4(*ip));
+                return Err(self.exhausted(IpNet::V4(self.v4), used));
+            }
+            Some(ip) => ip,
+        };
+        match self.v6 {
+            None => Ok((ip, None)),
+            Some(v6) => match preferred6.or_else(|| self.next_v6(used6)) {
+                None => {
+                    let used = used6.iter().map(|ip| IpAddr::V6(*ip));
+                    Err(self.exhausted(IpNet::V6(v6), used))
+                }
+                Some(ip6) => Ok((ip, Some(ip6))),
+            },
+        }
+    }
+
+    /// Lowest free IPv4 address, walks the pool lazily instead of building a set of it
+    fn next_v4(&self, used: &HashSet<Ipv4Addr>) -> Option<Ipv4Addr> {
+        self.v4
+            .hosts()
+            .find(|ip| !used.contains(ip) && !self.is_reserved(IpAddr::V4(*ip)))
+    }
+
+    /// IPv6 pools are too large to walk, reserved ranges are jumped over,
+    /// so at most one step is taken per used address and reserved range
+    fn next_v6(&self, used: &HashSet<Ipv6Addr>) -> Option<Ipv6Addr> {
+        let pool = IpNet::V6(self.v6?);
+        let (first, last) = host_range(pool)?;
+        let reserved = self.reserved_ranges(pool, (first, last));
+        let mut ip = first;
+        for _ in 0..=used.len() + reserved.len() {
+            if let Some((_, end)) = reserved
+                .iter()
+                .find(|(start, end)| (*start..=*end).contains(&ip))
+            {
+                ip = end.checked_add(1)?;
+            } else if used.contains(&Ipv6Addr::from(ip)) {
+                ip = ip.checked_add(1)?;
+            } else {
+                return Some(Ipv6Addr::from(ip));
+            }
+            if ip > last {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Capacity is counted from the prefix lengths, the pool is never walked
+    fn exhausted(&self, pool: IpNet, used: impl Iterator<Item = IpAddr>) -> PoolExhausted {
+        let capacity = match host_range(pool) {
+            None => 0,
+            Some((first, last)) => {
+                let reserved: u128 = self
+                    .reserved_ranges(pool, (first, last))
+                    .iter()
+                    .map(|(start, end)| end - start + 1)
+                    .sum();
+                (last - first + 1) - reserved
+            }
+        };
+        // Peers may keep addresses that were assigned before the pool was changed
+        let used = used
+            .filter(|ip| pool.contains(ip) && !self.is_reserved(*ip))
+            .count();
+        PoolExhausted {
+            pool,
+            used,
+            capacity,
+        }
+    }
+
+    /// Reserved networks of the pool family within the range, sorted and merged
+    fn reserved_ranges(&self, pool: IpNet, (first, last): (u128, u128)) -> Vec<(u128, u128)> {
+        let mut ranges: Vec<(u128, u128)> = self
+            .reserved
+            .iter()
+            .filter_map(|net| {
+                let (start, end) = match (pool, net) {
+                    (IpNet::V4(_), IpNet::V4(net)) => (
+                        u32::from(net.network()) as u128,
+                        u32::from(net.broadcast()) as u128,
+                    ),
+                    (IpNet::V6(_), IpNet::V6(net)) => {
+                        (u128::from(net.network()), u128::from(net.broadcast()))
+                    }
+                    _ => return None,
+                };
+                Some((start.max(first), end.min(last))).filter(|(start, end)| start <= end)
+            })
+            .collect();
+        ranges.sort();
+        let mut merged: Vec<(u128, u128)> = vec![];
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+    }
+
+    fn is_reserved(&self, ip: IpAddr) -> bool {
+        self.reserved.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// First and last address handed out of the pool, as numbers
+fn host_range(pool: IpNet) -> Option<(u128, u128)> {
+    match pool {
+        IpNet::V4(v4) => {
+            let network = u32::from(v4.network()) as u128;
+            let broadcast = u32::from(v4.broadcast()) as u128;
+            match v4.prefix_len() {
+                31.. => Some((network, broadcast)),
+                _ => Some((network + 1, broadcast - 1)),
+            }
+        }
+        IpNet::V6(v6) => {
+            let network = u128::from(v6.network());
+            // The first address is the subnet-router anycast address
+            match network < u128::from(v6.broadcast()) {
+                true => Some((network + 1, u128::from(v6.broadcast()))),
+                false => None,
+            }
+        }
+    }
+}
+
+fn parse_net(net: &str) -> SimpleResult<IpNet> {
+    if let Ok(net) = net.parse::<IpNet>() {
+        return Ok(net);
+    }
+    match net.parse::<IpAddr>() {
+        Err(why) => Err(SimpleError::with(
+            &format!("Cannot parse reserved address {}", net),
+            why,
+        )),
+        Ok(ip) => Ok(IpNet::from(ip)),
+    }
+}
+
+fn first_v6(net: &Ipv6Net) -> Ipv6Addr {
+    net.hosts().nth(1).unwrap_or(net.network())
+}
+
+#[cfg(test)]
+#[test]
+fn allocate_addresses() {
+    let pool = AddressPool::new("10.8.0.0/30", Some("fd00:8::/126"), None).unwrap();
+    let mut used = HashSet::new();
+    assert!(pool.next_v4(&used) == Some(Ipv4Addr::new(10, 8, 0, 2)));
+    used.insert(Ipv4Addr::new(10, 8, 0, 2));
+    assert!(pool.next_v4(&used).is_none());
+    assert!(pool.next_v6(&HashSet::new()) == Some("fd00:8::2".parse().unwrap()));
+    let pool = AddressPool::new("10.8.0.0/29", None, Some("10.8.0.0/30, 10.8.0.5")).unwrap();
+    assert!(pool.next_v4(&HashSet::new()) == Some(Ipv4Addr::new(10, 8, 0, 4)));
+    used.insert(Ipv4Addr::new(10, 8, 0, 4));
+    assert!(pool.next_v4(&used) == Some(Ipv4Addr::new(10, 8, 0, 6)));
+    assert!(pool.next_v6(&HashSet::new()).is_none());
+    used.insert(Ipv4Addr::new(10, 8, 0, 6));
+    let (none, none6) = (HashSet::new(), HashSet::new());
+    let (ip, _) = pool
+        .allocate(&none, &none6, (Some(Ipv4Addr::new(10, 8, 0, 6)), None))
+        .unwrap();
+    assert!(ip == Ipv4Addr::new(10, 8, 0, 6));
+    // Reserved and broadcast addresses are not kept
+    for preferred in [Ipv4Addr::new(10, 8, 0, 5), Ipv4Addr::new(10, 8, 0, 7)] {
+        let (ip, _) = pool
+            .allocate(&none, &none6, (Some(preferred), None))
+            .unwrap();
+        assert!(ip == Ipv4Addr::new(10, 8, 0, 4));
+    }
+    let exhausted = pool.allocate(&used, &none6, (None, None)).unwrap_err();
+    assert!(exhausted.used == 2 && exhausted.capacity == 2);
+    // Large IPv6 pools are neither walked nor counted one by one
+    let pool = AddressPool::new("10.8.0.0/24", Some("fd00:8::/64"), Some("fd00:8::/65")).unwrap();
+    let used6 = HashSet::from(["fd00:8::8000:0:0:0".parse().unwrap()]);
+    assert!(pool.next_v6(&used6) == Some("fd00:8::8000:0:0:1".parse().unwrap()));
+    let exhausted = pool.exhausted(IpNet::V6(pool.v6.unwrap()), std::iter::empty());
+    assert!(exhausted.capacity == 1 << 63);
+    let pool = AddressPool::new("10.8.0.0/24", Some("fd00:8::/64"), Some("fd00:8::/64")).unwrap();
+    assert!(pool.next_v6(&HashSet::new()).is_none());
+}
@@ -0,0 +1,277 @@
+use crate::admin::Admins;
+use crate::backend::DeviceState;
+use crate::mongo::Mongo;
+use crate::server::Servers;
+use crate::wireguard::{self, Peer};
+use ipnet::IpNet;
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+use teloxide::Bot;
+
+/// Peers being added while the interfaces are read look like drift, so it is checked again after a while
+const GRACE: Duration = Duration::from_secs(10);
+
+/// Difference between the db and an interface.
+pub struct Drift {
+    pub interface: String,
+    /// Stored peers that are missing on the interface or have other addresses there
+    pub missing: Vec<Peer>,
+    /// Public keys on the interface no stored peer has
+    pub unknown: Vec<String>,
+}
+
+/// Drift of every interface and what was done about it.
+#[derive(Default)]
+pub struct Report {
+    pub drifts: Vec<Drift>,
+    /// Interfaces that cannot be read and peers that cannot be fixed
+    pub errors: Vec<String>,
+    /// Missing peers were added back
+    pub restored: bool,
+    /// Unknown peers were removed
+    pub removed: bool,
+}
+
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        self.drifts.is_empty() && self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for drift in &self.drifts {
+            if !drift.missing.is_empty() {
+                let names: Vec<String> = drift
+                    .missing
+                    .iter()
+                    .map(|peer| format!("@{}", peer.username))
+                    .collect();
+                writeln!(
+                    f,
+                    "{}: {} missing peers ({}), {}",
+                    drift.interface,
+                    drift.missing.len(),
+                    names.join(", "),
+                    if self.restored {
+                        "restored"
+                    } else {
+                        "kept as is"
+                    }
+                )?;
+            }
+            if !drift.unknown.is_empty() {
+                writeln!(
+                    f,
+                    "{}: {} unknown peers ({}), {}",
+                    drift.interface,
+                    drift.unknown.len(),
+                    drift.unknown.join(", "),
+                    if self.removed {
+                        "removed"
+                    } else {
+                        "kept as is"
+                    }
+                )?;
+            }
+        }
+        for error in &self.errors {
+            writeln!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the db with the interfaces, restores missing peers if `restore` is set
+/// and removes peers the db does not know about if `remove_unknown` is set.
+pub async fn reconcile(
+    mongo: &Mongo,
+    servers: &Servers,
+    restore: bool,
+    remove_unknown: bool,
+) -> Report {
+    let mut report = Report {
+        restored: restore,
+        removed: remove_unknown,
+        ..Default::default()
+    };
+    let peers = match mongo.get_peers().await {
+        Err(why) => {
+            report.errors.push(format!("Cannot read peers: {}", why));
+            return report;
+        }
+        Ok(peers) => peers,
+    };
+    // Several servers may share an interface, so interfaces are compared as a whole
+    let mut interfaces: Vec<&str> = servers
+        .iter()
+        .map(|server| server.interface.as_str())
+        .collect();
+    interfaces.sort();
+    interfaces.dedup();
+    for interface in interfaces {
+        let server = servers
+            .iter()
+            .find(|server| server.interface == interface)
+            .unwrap();
+        let device = match server.backend.device(interface) {
+            Err(why) => {
+                report
+                    .errors
+                    .push(format!("Cannot read interface {}: {}", interface, why));
+                continue;
+            }
+            Ok(device) => device,
+        };
+        let stored: Vec<&Peer> = peers
+            .iter()
+            .filter(|peer| {
+                servers
+                    .find(&peer.server)
+                    .is_some_and(|server| server.interface == interface)
+            })
+            .collect();
+        let drift = diff(interface, &stored, &device);
+        if drift.missing.is_empty() && drift.unknown.is_empty() {
+            continue;
+        }
+        if restore {
+            for peer in &drift.missing {
+                let server = servers.find(&peer.server).unwrap();
+                if let Err(why) = wireguard::restore_peer(peer, server) {
+                    report
+                        .errors
+                        .push(format!("Cannot restore @{}: {}", peer.username, why));
+                }
+            }
+        }
+        if remove_unknown {
+            for public_key in &drift.unknown {
+                if let Err(why) = server.backend.remove_peer(interface, public_key) {
+                    report
+                        .errors
+                        .push(format!("Cannot remove {}: {}", public_key, why));
+                }
+            }
+        }
+        report.drifts.push(drift);
+    }
+    report
+}
+
+/// Checks the interfaces every `interval` and reports drift to the admins, once until it changes.
+/// With `heal` missing peers are restored, unknown ones are removed only if `remove_unknown` is set too.
+pub async fn watch(
+    bot: Bot,
+    mongo: Mongo,
+    servers: Servers,
+    admins: Admins,
+    interval: Duration,
+    heal: bool,
+    remove_unknown: bool,
+) {
+    let mut reported = String::new();
+    let mut timer = tokio::time::interval(interval);
+    timer.tick().await; // The first tick is immediate, startup has just reconciled
+    loop {
+        timer.tick().await;
+        if reconcile(&mongo, &servers, false, false).await.is_empty() {
+            reported.clear();
+            continue;
+        }
+        tokio::time::sleep(GRACE).await;
+        let report = reconcile(&mongo, &servers, heal, heal && remove_unknown).await;
+        let text = report.to_string();
+        if report.is_empty() || text == reported {
+            continue;
+        }
+        log::warn!("Interfaces differ from the db:\n{}", text);
+        admins
+            .notify(&bot, &format!("Drift check:\n{}", text), None)
+            .await;
+        // Healed drift is gone by the next check, so only drift that stays is not repeated
+        reported = text;
+    }
+}
+
+/// Peers without a config are not expected on the interface
+fn diff(interface: &str, peers: &[&Peer], device: &DeviceState) -> Drift {
+    let mut missing = vec![];
+    let mut known = HashSet::new();
+    for peer in peers {
+        let public_key = match (&peer.public_key, peer.ip) {
+            (Some(public_key), Some(_)) => public_key,
+            _ => continue,
+        };
+        known.insert(public_key.as_str());
+        let mut expected: Vec<String> = wireguard::allowed_ips(peer)
+            .iter()
+            .map(|ip| IpNet::from(*ip).to_string())
+            .collect();
+        expected.sort();
+        let applied = device.peers.iter().any(|state| {
+            let mut allowed_ips = state.allowed_ips.clone();
+            allowed_ips.sort();
+            &state.public_key == public_key && allowed_ips == expected
+        });
+        if !applied {
+            missing.push((*peer).clone());
+        }
+    }
+    let unknown = device
+        .peers
+        .iter()
+        .filter(|state| !known.contains(state.public_key.as_str()))
+        .map(|state| state.public_key.clone())
+        .collect();
+    Drift {
+        interface: interface.to_string(),
+        missing,
+        unknown,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn diff_interface() {
+    use crate::backend::PeerState;
+    use mongodb::bson::DateTime;
+    use std::net::Ipv4Addr;
+    let peer = |user_id: u64, key: Option<&str>, ip: Option<Ipv4Addr>| Peer {
+        user_id,
+        username: format!("user{}", user_id),
+        server: "default".to_string(),
+        public_key: key.map(str::to_string),
+        private_key: None,
+        preshared_key: None,
+        ip,
+        ip6: None,
+        date: DateTime::now(),
+    };
+    let state = |key: &str, ip: &str| PeerState {
+        public_key: key.to_string(),
+        allowed_ips: vec![ip.to_string()],
+        endpoint: None,
+        last_handshake: None,
+        rx_bytes: 0,
+        tx_bytes: 0,
+    };
+    let peers = [
+        peer(1, Some("a"), Some(Ipv4Addr::new(10, 0, 0, 2))),
+        peer(2, Some("b"), Some(Ipv4Addr::new(10, 0, 0, 3))),
+        peer(3, Some("c"), Some(Ipv4Addr::new(10, 0, 0, 4))),
+        peer(4, None, None),
+    ];
+    let device = DeviceState {
+        peers: vec![
+            state("a", "10.0.0.2/32"),
+            state("b", "10.0.0.9/32"),
+            state("d", "10.0.0.5/32"),
+        ],
+    };
+    let drift = diff("wg0", &peers.iter().collect::<Vec<&Peer>>(), &device);
+    let missing: Vec<u64> = drift.missing.iter().map(|peer| peer.user_id).collect();
+    assert!(missing == vec![2, 3]);
+    assert!(drift.unknown == vec!["d".to_string()]);
+}
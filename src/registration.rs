@@ -0,0 +1,30 @@
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+/// Request of a user to get access, kept after the decision to know where to reach the user.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Registration {
+    pub user_id: u64,
+    pub username: String,
+    pub chat_id: i64,
+    pub status: Status,
+    pub created: DateTime,
+    pub updated: DateTime,
+}
+
+/// Registration notification sent to an admin chat, replies to it are about the user.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Notification {
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub user_id: u64,
+    pub date: DateTime,
+}
@@ -0,0 +1,202 @@
+use crate::backend::{PeerState, WgBackend};
+use crate::pool::AddressPool;
+use configparser::ini::Ini;
+use simple_error::{SimpleError, SimpleResult};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const SECTION_PREFIX: &str = "server.";
+
+/// WireGuard server (location) a user can get a config for.
+pub struct Server {
+    pub name: String,
+    pub interface: String,
+    pub endpoint: String,
+    pub public_key: String,
+    pub pool: AddressPool,
+    pub dns: String,
+    pub keepalive: String,
+    /// wg-quick config or include file the peers are written to, besides the interface
+    pub peers_file: Option<String>,
+    /// New peers get a preshared key on top of their key pair
+    pub preshared_keys: bool,
+    pub backend: Arc<dyn WgBackend>,
+    /// Held while an address is picked and stored
+    pub allocation: Mutex<()>,
+    /// Held while the peers file is read from the db and written
+    pub writing: Mutex<()>,
+}
+
+/// All servers managed by the bot, the first one is the default.
+/// It is `[WireGuard] DefaultServer` if set, otherwise servers go in alphabetical order.
+#[derive(Clone)]
+pub struct Servers(Arc<Vec<Server>>);
+
+impl Servers {
+    /// Reads `[Server.<name>]` sections, or the legacy `[WireGuard]` + `[Peer]` pair as "default".
+    pub fn from_config(config: &Ini, backend: Arc<dyn WgBackend>) -> SimpleResult<Self> {
+        let mut servers = vec![];
+        for section in config.sections() {
+            if let Some(name) = section.strip_prefix(SECTION_PREFIX) {
+                servers.push(read_server(
+                    config,
+                    name,
+                    &section,
+                    &section,
+                    None,
+                    backend.clone(),
+                )?);
+            }
+        }
+        if servers.is_empty() {
+            servers.push(read_server(
+                config,
+                "default",
+                "Peer",
+                "WireGuard",
+                Some("wg0"),
+                backend,
+            )?);
+        }
+        servers.sort_by(|a, b| a.name.cmp(&b.name));
+        if let Some(name) = config.get("WireGuard", "DefaultServer") {
+            match servers.iter().position(|server| server.name == name) {
+                Some(index) => {
+                    let server = servers.remove(index);
+                    servers.insert(0, server);
+                }
+                None => return Err(SimpleError::new(format!("Unknown default server {}", name))),
+            }
+        }
+        Ok(Servers(Arc::new(servers)))
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Server> {
+        self.0.iter().find(|server| server.name == name)
+    }
+
+    pub fn default_server(&self) -> &Server {
+        &self.0[0]
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.0.iter().map(|server| server.name.as_str()).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Server> {
+        self.0.iter()
+    }
+
+    /// Interface state of the peers of every server by public key, interfaces that cannot be read are skipped
+    pub fn peer_states(&self) -> HashMap<String, PeerState> {
+        let mut states = HashMap::new();
+        for server in self.iter() {
+            match server.backend.device(&server.interface) {
+                Err(why) => log::error!("Cannot read interface {}: {}", server.interface, why),
+                Ok(device) => {
+                    for peer in device.peers {
+                        states.insert(peer.public_key.clone(), peer);
+                    }
+                }
+            }
+        }
+        states
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+fn read_server(
+    config: &Ini,
+    name: &str,
+    section: &str,
+    interface_section: &str,
+    default_interface: Option<&str>,
+    backend: Arc<dyn WgBackend>,
+) -> SimpleResult<Server> {
+    let required = |key: &str| -> SimpleResult<String> {
+        config
+            .get(section, key)
+            .ok_or_else(|| SimpleError::new(format!("Cannot find {} for server {}", key, name)))
+    };
+    let interface = match config.get(interface_section, "Interface") {
+        Some(interface) => interface,
+        None => match default_interface {
+            Some(interface) => interface.to_string(),
+            None => required("Interface")?,
+        },
+    };
+    Ok(Server {
+        name: name.to_string(),
+        interface,
+        endpoint: required("Endpoint")?,
+        public_key: required("Key")?,
+        pool: read_pool(config, name, section)?,
+        dns: config.get(section, "DNS").unwrap_or("8.8.8.8".to_string()),
+        keepalive: config.get(section, "KeepAlive").unwrap_or(25.to_string()),
+        peers_file: config.get(interface_section, "PeersFile"),
+        preshared_keys: match config.getbool(interface_section, "PresharedKeys") {
+            Err(why) => {
+                return Err(SimpleError::new(format!(
+                    "Cannot parse PresharedKeys of server {}: {}",
+                    name, why
+                )))
+            }
+            Ok(preshared_keys) => preshared_keys.unwrap_or(false),
+        },
+        backend,
+        allocation: Mutex::new(()),
+        writing: Mutex::new(()),
+    })
+}
+
+/// `Pool` and `Pool6` are CIDRs, legacy configs only have `Subnet` as the prefix of 10.0.0.0
+fn read_pool(config: &Ini, name: &str, section: &str) -> SimpleResult<AddressPool> {
+    let v4 = config.get(section, "Pool").unwrap_or(format!(
+        "10.0.0.0/{}",
+        config.get(section, "Subnet").unwrap_or(16.to_string())
+    ));
+    let v6 = config.get(section, "Pool6");
+    let reserved = config.get(section, "Reserved");
+    match AddressPool::new(&v4, v6.as_deref(), reserved.as_deref()) {
+        Err(why) => Err(SimpleError::with(
+            &format!("Cannot read address pool of server {}", name),
+            why,
+        )),
+        Ok(pool) => Ok(pool),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn read_servers() {
+    use crate::backend::CliBackend;
+    let mut config = Ini::new();
+    config
+        .read(
+            "[Server.Paris]
+Interface = wg1
+Endpoint = 127.0.0.2:51820
+Key = paris
+[Server.amsterdam]
+Interface = wg0
+Endpoint = 127.0.0.1:51820
+Key = amsterdam
+DNS = 1.1.1.1
+[WireGuard]
+DefaultServer = paris"
+                .to_string(),
+        )
+        .unwrap();
+    let backend = Arc::new(CliBackend {
+        wg_path: "/usr/bin/wg".to_string(),
+    });
+    let servers = Servers::from_config(&config, backend).unwrap();
+    assert!(servers.names() == vec!["paris", "amsterdam"]);
+    assert!(servers.default_server().interface == "wg1");
+    let amsterdam = servers.find("amsterdam").unwrap();
+    assert!(amsterdam.dns == "1.1.1.1" && amsterdam.pool.v4.to_string() == "10.0.0.0/16");
+}
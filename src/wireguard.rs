@@ -1,211 +1,474 @@
-use crate::mongo::Mongo;
+use crate::mongo::{Claim, Mongo};
+use crate::pool::PoolExhausted;
+use crate::server::{Server, Servers};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use configparser::ini::Ini;
+use ipnet::IpNet;
 use mongodb::bson::{doc, DateTime};
+use rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 use simple_error::{SimpleError, SimpleResult};
 use std::collections::HashSet;
-use std::io::Write;
-use std::net::Ipv4Addr;
-use std::process::{Command, Stdio};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 use tokio::sync::Mutex;
-#[derive(Serialize, Deserialize, Debug)]
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// How many times an address taken by a concurrent request is skipped before giving up
+const CLAIM_ATTEMPTS: usize = 16;
+/// `PrivateKey` of configs for keys the user generated, they fill in their own
+pub const PRIVATE_KEY_PLACEHOLDER: &str = "<your private key>";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Peer {
     pub user_id: u64,
     pub username: String,
+    /// Name of the server this peer is configured on, a user has one peer per server
+    pub server: String,
     pub public_key: Option<String>,
+    /// Only in memory while a config is built, if private keys are not stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub private_key: Option<String>,
+    /// Set if the server uses preshared keys, unlike the private key it is always stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<String>,
+    #[serde(default, with = "ip_string")]
     pub ip: Option<Ipv4Addr>,
+    #[serde(default, with = "ip_string")]
+    pub ip6: Option<Ipv6Addr>,
     pub date: DateTime,
 }
 
-pub async fn add_peer(peer: &mut Peer, mongo: &Mongo) -> SimpleResult<()> {
-    let (private_key, public_key) = gen_keys();
-    peer.private_key = Some(private_key);
+/// Addresses are stored as strings, so the unique indexes compare whole addresses.
+/// The driver serializes inserts in a binary form that would turn them into arrays of octets,
+/// such arrays written by older versions are still accepted.
+mod ip_string {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::str::FromStr;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Text(String),
+        Octets(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer, T: Display>(ip: &Option<T>, s: S) -> Result<S::Ok, S::Error> {
+        match ip {
+            Some(ip) => s.serialize_some(&ip.to_string()),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: FromStr>(d: D) -> Result<Option<T>, D::Error> {
+        let text = match Option::<Stored>::deserialize(d)? {
+            None => return Ok(None),
+            Some(Stored::Text(text)) => text,
+            Some(Stored::Octets(octets)) => match octets.len() {
+                4 => Ipv4Addr::from(<[u8; 4]>::try_from(octets).unwrap()).to_string(),
+                16 => Ipv6Addr::from(<[u8; 16]>::try_from(octets).unwrap()).to_string(),
+                _ => return Err(serde::de::Error::custom("Wrong number of address octets")),
+            },
+        };
+        match text.parse() {
+            Err(_) => Err(serde::de::Error::custom(format!(
+                "Cannot parse address {}",
+                text
+            ))),
+            Ok(ip) => Ok(Some(ip)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PeerError {
+    /// The server has no free addresses left
+    PoolExhausted(PoolExhausted),
+    /// Another peer of the server has the public key the user sent
+    KeyInUse,
+    Failed(SimpleError),
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerError::PoolExhausted(why) => write!(f, "{}", why),
+            PeerError::KeyInUse => write!(f, "The public key is already in use"),
+            PeerError::Failed(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl std::error::Error for PeerError {}
+
+impl From<SimpleError> for PeerError {
+    fn from(why: SimpleError) -> Self {
+        PeerError::Failed(why)
+    }
+}
+
+/// Adds the peer with new keys, or with the public key the user generated, the addresses it already has are kept.
+/// The previous key of the peer is replaced on the interface.
+pub async fn add_peer(
+    peer: &mut Peer,
+    mongo: &Mongo,
+    server: &Server,
+    public_key: Option<String>,
+) -> Result<(), PeerError> {
+    let previous = mongo.find(peer.user_id, &peer.server).await;
+    let (private_key, public_key) = match public_key {
+        Some(public_key) => {
+            // The index covers one server, the interface also has the peers of servers sharing it
+            let own = previous.as_ref().and_then(|p| p.public_key.as_ref()) == Some(&public_key);
+            if !own && on_interface(&public_key, server)? {
+                return Err(PeerError::KeyInUse);
+            }
+            (None, public_key)
+        }
+        None => {
+            let (private_key, public_key) = gen_keys()?;
+            (Some(private_key), public_key)
+        }
+    };
+    peer.private_key = private_key;
     peer.public_key = Some(public_key);
-    peer.ip = Some(get_ip(&mut mongo.get_peers().await));
-    let mut wg = match Command::new("/usr/bin/wg")
-        .args([
-            "set",
-            "wg0",
-            "peer",
-            format!("{}", peer.public_key.clone().unwrap()).as_str(),
-            "allowed-ips",
-            format!("{}/32", peer.ip.unwrap()).as_str(),
-        ])
-        .spawn()
-    {
-        Err(why) => return Err(SimpleError::from(why)),
-        Ok(wg) => wg,
+    peer.preshared_key = match server.preshared_keys {
+        true => Some(gen_preshared_key()?),
+        false => None,
     };
-    match wg.wait() {
-        Err(why) => Err(SimpleError::from(why)),
-        Ok(_) => Ok(()),
+    // The key is stored with the addresses, so the unique index rejects a key taken meanwhile
+    claim_ip(peer, mongo, server, true).await?;
+    let old_key = previous.as_ref().and_then(|p| p.public_key.clone());
+    apply_or_rollback(peer, previous, mongo, server).await?;
+    // The old key goes only once the new one works, so a failure never leaves the user without a tunnel
+    if let Some(old_key) = old_key.filter(|key| peer.public_key.as_ref() != Some(key)) {
+        if let Err(why) = server.backend.remove_peer(&server.interface, &old_key) {
+            log::error!("Cannot remove old key of {}: {}", peer.username, why);
+        }
     }
+    Ok(())
 }
 
-pub async fn remove_peer(peer: &Peer) -> SimpleResult<()> {
-    let mut wg = match Command::new("/usr/bin/wg")
-        .args([
-            "set",
-            "wg0",
-            "peer",
-            format!("{}", peer.public_key.clone().unwrap()).as_str(),
-            "remove",
-        ])
-        .spawn()
+/// The interface knows peers by key, a key of another peer on any server of the interface
+/// would take that peer over. Checked before the old peer is replaced.
+pub async fn key_in_use(
+    public_key: &str,
+    peer: &Peer,
+    mongo: &Mongo,
+    servers: &Servers,
+    server: &Server,
+) -> SimpleResult<bool> {
+    let shared = |name: &str| {
+        servers
+            .find(name)
+            .is_some_and(|other| other.interface == server.interface)
+    };
+    if servers
+        .iter()
+        .any(|other| shared(&other.name) && other.public_key == public_key)
     {
-        Err(why) => return Err(SimpleError::from(why)),
-        Ok(wg) => wg,
+        return Ok(true);
+    }
+    Ok(mongo
+        .find_by_key(public_key)
+        .await?
+        .iter()
+        .any(|p| (p.user_id, &p.server) != (peer.user_id, &peer.server) && shared(&p.server)))
+}
+
+fn on_interface(public_key: &str, server: &Server) -> SimpleResult<bool> {
+    match server.backend.device(&server.interface) {
+        Err(why) => Err(SimpleError::from(why)),
+        Ok(device) => Ok(device.peers.iter().any(|p| p.public_key == public_key)),
+    }
+}
+
+/// Moves the peer to new addresses, its keys stay the same
+pub async fn change_ip(peer: &mut Peer, mongo: &Mongo, server: &Server) -> Result<(), PeerError> {
+    let previous = mongo.find(peer.user_id, &peer.server).await;
+    claim_ip(peer, mongo, server, false).await?;
+    if peer.public_key.is_none() {
+        return Ok(());
+    }
+    apply_or_rollback(peer, previous, mongo, server).await
+}
+
+/// Puts a stored peer back on the interface with the keys and addresses it has
+pub fn restore_peer(peer: &Peer, server: &Server) -> Result<(), PeerError> {
+    if peer.public_key.is_none() || peer.ip.is_none() {
+        return Err(SimpleError::new(format!("Peer {} has no config", peer.username)).into());
+    }
+    apply_peer(peer, server)
+}
+
+/// Addresses the interface routes to the peer
+pub fn allowed_ips(peer: &Peer) -> Vec<IpAddr> {
+    let mut allowed_ips: Vec<IpAddr> = peer.ip.map(IpAddr::V4).into_iter().collect();
+    allowed_ips.extend(peer.ip6.map(IpAddr::V6));
+    allowed_ips
+}
+
+/// The addresses are stored before the peer is applied, so if that fails
+/// the db and the interface get the previous peer back
+async fn apply_or_rollback(
+    peer: &Peer,
+    previous: Option<Peer>,
+    mongo: &Mongo,
+    server: &Server,
+) -> Result<(), PeerError> {
+    let why = match apply_peer(peer, server) {
+        Ok(_) => return Ok(()),
+        Err(why) => why,
+    };
+    let rollback = match &previous {
+        Some(previous) => mongo.update(previous).await,
+        None => mongo.delete(peer).await,
     };
-    match wg.wait() {
+    if let Err(rollback) = rollback {
+        log::error!("Cannot roll back peer {}: {}", peer.username, rollback);
+    }
+    if let Some(public_key) = &peer.public_key {
+        if previous.as_ref().and_then(|p| p.public_key.as_ref()) != Some(public_key) {
+            let _ = server.backend.remove_peer(&server.interface, public_key);
+        }
+    }
+    if let Some(previous) = previous.filter(|p| p.public_key.is_some() && p.ip.is_some()) {
+        if let Err(rollback) = apply_peer(&previous, server) {
+            log::error!("Cannot restore peer {}: {}", previous.username, rollback);
+        }
+    }
+    Err(why)
+}
+
+fn apply_peer(peer: &Peer, server: &Server) -> Result<(), PeerError> {
+    let public_key = peer.public_key.as_ref().unwrap();
+    let allowed_ips = allowed_ips(peer);
+    if let Err(why) = server.backend.set_peer(
+        &server.interface,
+        public_key,
+        peer.preshared_key.as_deref(),
+        &allowed_ips,
+    ) {
+        return Err(SimpleError::from(why).into());
+    }
+    // Read the interface back, so a silently ignored update is reported as an error
+    let device = match server.backend.device(&server.interface) {
+        Err(why) => return Err(SimpleError::from(why).into()),
+        Ok(device) => device,
+    };
+    let mut allowed_ips: Vec<String> = allowed_ips
+        .iter()
+        .map(|ip| IpNet::from(*ip).to_string())
+        .collect();
+    allowed_ips.sort();
+    if device.peers.iter().any(|p| {
+        let mut peer_ips = p.allowed_ips.clone();
+        peer_ips.sort();
+        &p.public_key == public_key && peer_ips == allowed_ips
+    }) {
+        Ok(())
+    } else {
+        Err(SimpleError::new(format!(
+            "Peer {} is missing on {} after update",
+            public_key, server.interface
+        ))
+        .into())
+    }
+}
+
+pub async fn remove_peer(peer: &Peer, server: &Server) -> SimpleResult<()> {
+    let public_key = match &peer.public_key {
+        None => return Ok(()),
+        Some(public_key) => public_key,
+    };
+    match server.backend.remove_peer(&server.interface, public_key) {
         Err(why) => Err(SimpleError::from(why)),
         Ok(_) => Ok(()),
     }
 }
 
-pub async fn gen_conf(peer: &Peer, conf: Arc<Mutex<Ini>>) -> SimpleResult<String> {
+pub async fn gen_conf(peer: &Peer, conf: Arc<Mutex<Ini>>, server: &Server) -> String {
     let mut config = Ini::new_cs();
     config.set(
         "Interface",
         "PrivateKey",
-        Some(peer.private_key.clone().unwrap()),
-    );
-    config.set(
-        "Interface",
-        "Address",
-        Some(format!(
-            "{}/{}",
-            peer.ip.unwrap().to_string(),
-            conf.lock()
-                .await
-                .get("Peer", "Subnet")
-                .unwrap_or(16.to_string())
-        )),
-    );
-    config.set(
-        "Interface",
-        "DNS",
         Some(
-            conf.lock()
-                .await
-                .get("Peer", "DNS")
-                .unwrap_or("8.8.8.8".to_string()),
+            peer.private_key
+                .clone()
+                .unwrap_or(PRIVATE_KEY_PLACEHOLDER.to_string()),
         ),
     );
-    config.set("Peer", "PublicKey", conf.lock().await.get("Peer", "Key"));
-    config.set(
-        "Peer",
-        "Endpoint",
-        conf.lock().await.get("Peer", "Endpoint"),
-    );
-    config.set("Peer", "AllowedIPs", Some("0.0.0.0/0".to_string()));
+    config.set("Interface", "Address", Some(address(peer, server)));
+    config.set("Interface", "DNS", Some(server.dns.clone()));
+    config.set("Peer", "PublicKey", Some(server.public_key.clone()));
+    if let Some(preshared_key) = &peer.preshared_key {
+        config.set("Peer", "PresharedKey", Some(preshared_key.clone()));
+    }
+    config.set("Peer", "Endpoint", Some(server.endpoint.clone()));
     config.set(
         "Peer",
-        "PersistentKeepalive",
+        "AllowedIPs",
         Some(
             conf.lock()
                 .await
-                .get("Peer", "KeepAlive")
-                .unwrap_or(25.to_string()),
+                .get("WireGuard", "AllowedIPs")
+                .unwrap_or(match server.pool.v6 {
+                    Some(_) => "0.0.0.0/0, ::/0".to_string(),
+                    None => "0.0.0.0/0".to_string(),
+                }),
         ),
     );
-    let config_path = format!(
-        "{}/{}.conf",
-        dirs::home_dir().unwrap().to_string_lossy(),
-        peer.username
+    config.set(
+        "Peer",
+        "PersistentKeepalive",
+        Some(server.keepalive.clone()),
     );
-    match config.write(&config_path) {
-        Err(why) => {
-            log::error!("Cannot save a client config: {}", why);
-            Err(SimpleError::from(why))
-        }
-        Ok(_) => Ok(config_path),
-    }
+    // Only kept in memory, the private key of the user never touches the disk
+    config.writes()
 }
 
-fn get_ip(peers: &mut Vec<Peer>) -> Ipv4Addr {
-    let mut ip_set = HashSet::new();
-    for i in 0..255 {
-        for j in 2..255 {
-            ip_set.insert(Ipv4Addr::new(10, 0, i, j));
-        }
+/// File name of the config, WireGuard apps name the tunnel after it and accept up to
+/// 15 letters, digits and `_=+.-`. Anything else in the server name is replaced with `_`,
+/// dots too, so the name can never be `..` or point to another directory.
+pub fn conf_name(server: &str) -> String {
+    let name: String = server
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '=' | '+' | '-' => c,
+            _ => '_',
+        })
+        .take(15)
+        .collect();
+    match name.trim_matches('_').is_empty() {
+        true => "wireguard.conf".to_string(),
+        false => format!("{}.conf", name),
     }
-    let peers_ip_set: HashSet<Ipv4Addr> = peers.into_iter().flat_map(|peer| peer.ip).collect();
-    ip_set.difference(&peers_ip_set).next().unwrap().to_owned()
 }
 
-fn gen_keys() -> (String, String) {
-    let genkey_process = match Command::new("/usr/bin/wg")
-        .arg("genkey")
-        .stdout(Stdio::piped())
-        .spawn()
-    {
-        Err(why) => panic!("Could not run wg genkey: {}", why),
-        Ok(genkey_process) => genkey_process,
-    };
-
-    let genkey_output = match genkey_process.wait_with_output() {
-        Err(why) => panic!("Could not run wg genkey: {}", why),
-        Ok(genkey_output) => genkey_output,
-    };
-
-    if !genkey_output.status.success() {
-        panic!(
-            "wg genkey finished with code {}",
-            String::from_utf8(genkey_output.stderr).unwrap()
-        );
+/// Dual-stack `Address` line, prefixes are the ones of the server pools
+fn address(peer: &Peer, server: &Server) -> String {
+    let mut address = format!("{}/{}", peer.ip.unwrap(), server.pool.v4.prefix_len());
+    if let (Some(ip6), Some(v6)) = (peer.ip6, server.pool.v6) {
+        address.push_str(&format!(", {}/{}", ip6, v6.prefix_len()));
     }
+    address
+}
 
-    let private_key =
-        String::from_utf8(genkey_output.stdout).expect("Cannot convert wg genkey to string");
-
-    let mut pubkey_process = match Command::new("/usr/bin/wg")
-        .arg("pubkey")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-    {
-        Err(why) => panic!("Could not run wg pubkey: {}", why),
-        Ok(pubkey_process) => pubkey_process,
-    };
+/// Takes free addresses of the server pool and stores them in the db right away,
+/// `keep` leaves the peer on its current addresses if they are still valid.
+/// Requests of this bot wait for each other, the unique index rejects an address taken
+/// by another bot instance meanwhile, then the next one is tried.
+async fn claim_ip(
+    peer: &mut Peer,
+    mongo: &Mongo,
+    server: &Server,
+    keep: bool,
+) -> Result<(), PeerError> {
+    let _guard = server.allocation.lock().await;
+    let peers = mongo.get_peers().await?;
+    let peers = peers
+        .iter()
+        .filter(|p| p.server == server.name && p.user_id != peer.user_id);
+    let mut used: HashSet<Ipv4Addr> = peers.clone().flat_map(|p| p.ip).collect();
+    let mut used6: HashSet<Ipv6Addr> = peers.flat_map(|p| p.ip6).collect();
+    let mut preferred = (peer.ip, peer.ip6);
+    if !keep {
+        used.extend(peer.ip);
+        used6.extend(peer.ip6);
+        preferred = (None, None);
+    }
+    for _ in 0..CLAIM_ATTEMPTS {
+        let (ip, ip6) = match server.pool.allocate(&used, &used6, preferred) {
+            Err(why) => return Err(PeerError::PoolExhausted(why)),
+            Ok(ips) => ips,
+        };
+        peer.ip = Some(ip);
+        peer.ip6 = ip6;
+        match mongo.claim_ip(peer).await? {
+            Claim::Stored => return Ok(()),
+            Claim::KeyTaken => return Err(PeerError::KeyInUse),
+            Claim::AddressTaken => (),
+        }
+        used.insert(ip);
+        used6.extend(ip6);
+    }
+    Err(SimpleError::new(format!(
+        "Cannot claim an address on {} after {} attempts",
+        server.name, CLAIM_ATTEMPTS
+    ))
+    .into())
+}
 
-    match pubkey_process
-        .stdin
-        .take()
-        .unwrap()
-        .write_all(&private_key.as_bytes())
-    {
-        Err(why) => panic!("Couldn't write to wg pubkey stdin: {}", why),
-        Ok(_) => (),
+fn gen_keys() -> SimpleResult<(String, String)> {
+    let mut private_key = [0u8; 32];
+    if let Err(why) = OsRng.try_fill_bytes(&mut private_key) {
+        return Err(SimpleError::with("Cannot generate private key", why));
     }
+    // Clamp the scalar the same way `wg genkey` does
+    private_key[0] &= 248;
+    private_key[31] &= 127;
+    private_key[31] |= 64;
+    let private_key = StaticSecret::from(private_key);
+    let public_key = PublicKey::from(&private_key);
+    Ok((
+        STANDARD.encode(private_key.to_bytes()),
+        STANDARD.encode(public_key.as_bytes()),
+    ))
+}
 
-    let pubkey_output = match pubkey_process.wait_with_output() {
-        Err(why) => panic!("Could not run wg genkey: {}", why),
-        Ok(pubkey_output) => pubkey_output,
+/// Checks a public key sent by a user is 32 base64 bytes, the way `wg pubkey` prints them
+pub fn parse_public_key(key: &str) -> SimpleResult<String> {
+    let key: [u8; 32] = match STANDARD.decode(key.trim()).map(<[u8; 32]>::try_from) {
+        Ok(Ok(key)) => key,
+        _ => {
+            return Err(SimpleError::new(
+                "A public key is 32 bytes in base64, like the output of wg pubkey",
+            ))
+        }
     };
-
-    if !pubkey_output.status.success() {
-        panic!(
-            "wg pubkey finished with code {}",
-            String::from_utf8(pubkey_output.stderr).unwrap()
-        );
+    // The all-zero point gives the same shared secret with every private key
+    if key == [0u8; 32] {
+        return Err(SimpleError::new(
+            "The public key is not a valid Curve25519 key",
+        ));
     }
-    let public_key =
-        String::from_utf8(pubkey_output.stdout).expect("Cannot convert wg pubkey to string");
+    Ok(STANDARD.encode(key))
+}
 
-    (
-        private_key.trim().to_string(),
-        public_key.trim().to_string(),
-    )
+/// Random 32 bytes, the same as `wg genpsk`
+fn gen_preshared_key() -> SimpleResult<String> {
+    let mut preshared_key = [0u8; 32];
+    if let Err(why) = OsRng.try_fill_bytes(&mut preshared_key) {
+        return Err(SimpleError::with("Cannot generate preshared key", why));
+    }
+    Ok(STANDARD.encode(preshared_key))
 }
 
 #[cfg(test)]
 #[test]
 fn generate_keys() {
-    let (private, public) = gen_keys();
-    println!("{}", private.len());
+    let (private, public) = gen_keys().unwrap();
     assert!(private.len() == 44 && public.len() == 44);
+    let private: [u8; 32] = STANDARD.decode(private).unwrap().try_into().unwrap();
+    let derived = PublicKey::from(&StaticSecret::from(private));
+    assert!(STANDARD.encode(derived.as_bytes()) == public);
+    let preshared = gen_preshared_key().unwrap();
+    assert!(preshared.len() == 44 && preshared != gen_preshared_key().unwrap());
+    assert!(parse_public_key(&format!(" {}\n", public)).unwrap() == public);
+    assert!(parse_public_key(&STANDARD.encode([0u8; 32])).is_err());
+    assert!(parse_public_key(&STANDARD.encode([1u8; 31])).is_err());
+    assert!(parse_public_key("not a key").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn name_conf() {
+    assert!(conf_name("amsterdam") == "amsterdam.conf");
+    assert!(conf_name("../../etc/passwd") == "______etc_passw.conf");
+    assert!(conf_name("..") == "wireguard.conf");
 }
 
 #[cfg(test)]
@@ -221,3 +484,145 @@ async fn read_conf() {
     let name = config.lock().await.get("Mongo", "Name").unwrap();
     assert!(name == "gimmewire");
 }
+
+#[cfg(test)]
+#[test]
+fn store_ip_as_string() {
+    use mongodb::bson::{from_slice, to_raw_document_buf};
+    let peer = Peer {
+        user_id: 1,
+        username: "User".to_string(),
+        server: "default".to_string(),
+        public_key: None,
+        private_key: None,
+        preshared_key: None,
+        ip: Some(Ipv4Addr::new(10, 0, 0, 2)),
+        ip6: None,
+        date: DateTime::now(),
+    };
+    let stored = to_raw_document_buf(&peer).unwrap();
+    assert!(stored.get_str("ip").unwrap() == "10.0.0.2");
+    let legacy = to_raw_document_buf(&doc! {
+        "user_id": 1_i64,
+        "username": "User",
+        "server": "default",
+        "ip": [10, 0, 0, 3],
+        "date": DateTime::now(),
+    })
+    .unwrap();
+    let peer: Peer = from_slice(legacy.as_bytes()).unwrap();
+    assert!(peer.ip == Some(Ipv4Addr::new(10, 0, 0, 3)) && peer.ip6.is_none());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn concurrent_allocation() {
+    use crate::backend::{DeviceState, PeerState, WgBackend, WgError};
+    use crate::pool::AddressPool;
+    use futures::future::join_all;
+
+    /// Keeps peers in memory instead of a real interface
+    #[derive(Default)]
+    struct MemoryBackend(std::sync::Mutex<Vec<PeerState>>);
+
+    impl WgBackend for MemoryBackend {
+        fn set_peer(
+            &self,
+            _: &str,
+            public_key: &str,
+            _: Option<&str>,
+            ips: &[IpAddr],
+        ) -> Result<(), WgError> {
+            self.0.lock().unwrap().push(PeerState {
+                public_key: public_key.to_string(),
+                allowed_ips: ips.iter().map(|ip| IpNet::from(*ip).to_string()).collect(),
+                endpoint: None,
+                last_handshake: None,
+                rx_bytes: 0,
+                tx_bytes: 0,
+            });
+            Ok(())
+        }
+        fn remove_peer(&self, _: &str, public_key: &str) -> Result<(), WgError> {
+            self.0
+                .lock()
+                .unwrap()
+                .retain(|p| p.public_key != public_key);
+            Ok(())
+        }
+        fn device(&self, _: &str) -> Result<DeviceState, WgError> {
+            Ok(DeviceState {
+                peers: self.0.lock().unwrap().clone(),
+            })
+        }
+    }
+
+    let mongo = Mongo::new(
+        "mongodb://localhost:27017",
+        "gimmewire".to_string(),
+        "concurrent_allocation".to_string(),
+    )
+    .await;
+    mongo.drop().await;
+    mongo.create_indexes().await.unwrap();
+    let server = Server {
+        name: "default".to_string(),
+        interface: "wg0".to_string(),
+        endpoint: "127.0.0.1:51820".to_string(),
+        public_key: "key".to_string(),
+        pool: AddressPool::new("10.0.0.0/24", Some("fd00::/120"), None).unwrap(),
+        dns: "8.8.8.8".to_string(),
+        keepalive: "25".to_string(),
+        peers_file: None,
+        preshared_keys: false,
+        backend: Arc::new(MemoryBackend::default()),
+        allocation: Mutex::new(()),
+        writing: Mutex::new(()),
+    };
+    let results = join_all((0..64).map(|user_id| {
+        let (mongo, server) = (&mongo, &server);
+        async move {
+            let mut peer = Peer {
+                user_id,
+                username: format!("User{}", user_id),
+                server: server.name.clone(),
+                public_key: None,
+                private_key: None,
+                preshared_key: None,
+                ip: None,
+                ip6: None,
+                date: DateTime::now(),
+            };
+            add_peer(&mut peer, mongo, server, None).await.map(|_| peer)
+        }
+    }))
+    .await;
+    let peers: Vec<Peer> = results.into_iter().map(Result::unwrap).collect();
+    let ips: HashSet<Ipv4Addr> = peers.iter().flat_map(|peer| peer.ip).collect();
+    let ips6: HashSet<Ipv6Addr> = peers.iter().flat_map(|peer| peer.ip6).collect();
+    assert!(ips.len() == peers.len() && ips6.len() == peers.len());
+    assert!(mongo.get_peers().await.unwrap().len() == peers.len());
+    // The lock keeps the claims above apart, so the unique index is checked directly
+    let claim = |user_id: u64| Peer {
+        user_id,
+        username: format!("User{}", user_id),
+        server: server.name.clone(),
+        public_key: None,
+        private_key: None,
+        preshared_key: None,
+        ip: Some(Ipv4Addr::new(10, 0, 0, 250)),
+        ip6: None,
+        date: DateTime::now(),
+    };
+    assert!(mongo.claim_ip(&claim(100)).await.unwrap() == Claim::Stored);
+    assert!(mongo.claim_ip(&claim(101)).await.unwrap() == Claim::AddressTaken);
+    assert!(mongo.find(101, &server.name).await.is_none());
+    // A key of another peer is rejected the same way
+    let taken = Peer {
+        public_key: peers[0].public_key.clone(),
+        ip: Some(Ipv4Addr::new(10, 0, 0, 251)),
+        ..claim(102)
+    };
+    assert!(mongo.claim_ip(&taken).await.unwrap() == Claim::KeyTaken);
+    mongo.drop().await;
+}